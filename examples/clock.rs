@@ -0,0 +1,76 @@
+// Streams the time as Server-Sent Events from "/events", one tick a second. The event ids count up,
+// so clients reconnecting with a Last-Event-ID get the ticks they missed.
+//
+//     cargo run --example clock -- --port 8080
+//     curl -N http://localhost:8080/events
+
+use std::{
+    process::exit,
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH}
+};
+
+use ws2::{
+    config::{self, Config},
+    http,
+    log,
+    logging::{Logger, LogLevel},
+    request::{Method, Request},
+    response::{Response, Status},
+    sse
+};
+
+
+const TOPIC: &str = "clock";
+
+
+fn main() {
+    let logger = Logger::new(LogLevel::Info);
+
+    let cfg = match config::load_config() {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            log!(logger, LogLevel::Error, "Config error: {}", e);
+            exit(1);
+        }
+    };
+
+    let server = http::Server::bind(cfg.ip, cfg.port, logger.clone())
+        .and_then(|server| server.with_config(cfg));
+
+    match server {
+        Ok(server) => {
+            start_clock(server.publisher());
+            server.listen(on_request)
+        },
+        Err(e) => log!(logger, LogLevel::Error, "Server error: {}", e)
+    }
+}
+
+
+// Send the time to every event stream each second
+fn start_clock(publisher: http::Publisher) {
+    thread::spawn(move || {
+        for tick in 1u64.. {
+            thread::sleep(Duration::from_secs(1));
+
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            let event = sse::Event::new(now.as_secs().to_string())
+                .with_id(tick.to_string())
+                .with_event("tick")
+                .with_retry(Duration::from_secs(1));
+
+            publisher.send_event(TOPIC, event);
+        }
+    });
+}
+
+
+// HEAD requests get the same head as GET ones, without starting a stream
+fn on_request(request: Request, _config: Arc<Config>, _logger: Logger) -> Response {
+    match request.method {
+        Method::Get | Method::Head if request.path == "/events" => Response::event_stream(TOPIC),
+        _ => Response::text(Status::NotFound, "404 Not Found")
+    }
+}
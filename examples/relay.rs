@@ -0,0 +1,66 @@
+// Chat rooms over WebSockets: every connection joins the room named after its path, and messages
+// are relayed to everyone in the room (the sender included). Plain HTTP requests get a 404.
+//
+//     cargo run --example relay -- --port 8080
+
+use std::{process::exit, sync::Arc};
+
+use ws2::{
+    config::{self, Config},
+    http,
+    log,
+    logging::{Logger, LogLevel},
+    request::Request,
+    response::{Response, Status},
+    websocket::{self, WebSocket}
+};
+
+
+fn main() {
+    let logger = Logger::new(LogLevel::Info);
+
+    let cfg = match config::load_config() {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            log!(logger, LogLevel::Error, "Config error: {}", e);
+            exit(1);
+        }
+    };
+
+    let server = http::Server::bind(cfg.ip, cfg.port, logger.clone())
+        .and_then(|server| server.with_config(cfg));
+
+    match server {
+        Ok(server) => server
+            .with_websocket(on_websocket)
+            .listen(on_request),
+        Err(e) => log!(logger, LogLevel::Error, "Server error: {}", e)
+    }
+}
+
+
+fn on_request(_request: Request, _config: Arc<Config>, _logger: Logger) -> Response {
+    Response::text(Status::NotFound, "404 Not Found")
+}
+
+
+fn on_websocket(socket: &mut WebSocket, event: websocket::Event, _config: Arc<Config>, logger: Logger) {
+    match event {
+        websocket::Event::Open(request) => {
+            log!(logger, LogLevel::Info, "WebSocket {} joined {}", socket.id(), request.path);
+            socket.subscribe(&request.path);
+        },
+
+        websocket::Event::Message(message) => {
+            let rooms: Vec<String> = socket.topics().map(String::from).collect();
+
+            for room in rooms {
+                socket.publish(&room, message.clone());
+            }
+        },
+
+        websocket::Event::Close(code, reason) => {
+            log!(logger, LogLevel::Info, "WebSocket {} left: {} {}", socket.id(), code, reason);
+        }
+    }
+}
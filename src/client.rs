@@ -1,125 +1,616 @@
 // A singular HTTP connection
 
-use std::{collections::{HashMap, VecDeque}, io::{self, Write}, net::{SocketAddr, TcpStream}, time::Duration};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::{Duration, Instant}
+};
 
-use crate::response::Response;
+use polling::Event;
+
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::{request::Parser, response::{Encoder, Response}, sse::EventStream, websocket::WebSocket};
+
+
+// Keys are made of a slot index (plus one, so the listener's key 0 is never handed out) in the
+// low half and the slot's generation in the high half. Generations wrap before reaching the top
+// bit, keeping keys clear of the ones reserved by the server & poller near `usize::MAX`.
+const INDEX_BITS: u32 = usize::BITS / 2;
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+const GENERATION_MASK: usize = (1 << (INDEX_BITS - 1)) - 1;
+
+
+// What an HTTP connection is waiting on, each has its own timeout
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Idle, // The next request
+    Head(Instant), // The rest of a request head, which started arriving then
+    Body(Instant), // The rest of a request body, which started arriving then
+    Writing(Instant) // The client reading queued output, which it last made progress on then
+}
+
+
+// Space for one client. The generation goes up every time the slot is emptied, so keys of removed
+// clients (e.g. in events that were already waiting) don't match whoever gets the slot next.
+#[derive(Debug, Default)]
+struct Slot {
+    generation: usize,
+    client: Option<Client>
+}
 
 
 #[derive(Debug)]
 pub struct Clients {
-    clients: HashMap<usize, Client>,
-    lifetimes: VecDeque<usize> // Indices into the HashMap, lowest (front) -> highest (end)
+    slots: Vec<Slot>,
+    free: Vec<usize>, // Indices of empty slots, reused before any new ones are added
+    len: usize,
+    deadlines: BinaryHeap<Reverse<(Instant, usize)>>, // Client deadlines & keys, soonest first (see `schedule`)
+    topics: HashMap<String, HashSet<usize>> // Keys of the WebSocket clients subscribed to each topic
 }
 
 impl Clients {
     pub fn new() -> Self {
         Clients {
-            clients: HashMap::new(),
-            lifetimes: VecDeque::new()
+            slots: vec![],
+            free: vec![],
+            len: 0,
+            deadlines: BinaryHeap::new(),
+            topics: HashMap::new()
         }
     }
 
     pub fn len(&self) -> usize {
-        self.clients.len()
+        self.len
     }
 
-    // Creates & adds a client, returns its associated key
-    pub fn add(&mut self, stream: TcpStream, address: SocketAddr) -> (usize, &Client) {
-        let key = address.port() as usize;
-        let client = Client::new(stream, address);
+    // Creates & adds a client, returns its associated key. The stream is handed back if every key
+    // is taken, which can happen with 16-bit slot indices on 32-bit targets.
+    pub fn add(&mut self, stream: TcpStream, address: SocketAddr, timeout: Duration) -> Result<(usize, &Client), TcpStream> {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None if self.slots.len() < INDEX_MASK => {
+                self.slots.push(Slot::default());
+                self.slots.len() - 1
+            },
+            None => return Err(stream)
+        };
 
-        self.clients.insert(key, client);
-        self.lifetimes.push_back(key);
-        (key, &self.clients[&key])
+        let key = (self.slots[index].generation << INDEX_BITS) | (index + 1);
+        let deadline = Instant::now() + timeout;
+
+        self.slots[index].client = Some(Client::new(key, stream, address, deadline));
+        self.len += 1;
+        self.schedule(key, deadline);
+
+        Ok((key, self.slots[index].client.as_ref().unwrap()))
+    }
+
+    // Index of the slot a key points to, if it hasn't been emptied since the key was handed out
+    fn index(&self, key: usize) -> Option<usize> {
+        let index = (key & INDEX_MASK).checked_sub(1)?;
+        let slot = self.slots.get(index)?;
+
+        (slot.generation == key >> INDEX_BITS).then_some(index)
     }
 
     pub fn get(&self, key: usize) -> Option<&Client> {
-        self.clients.get(&key)
+        self.slots[self.index(key)?].client.as_ref()
     }
 
     pub fn get_mut(&mut self, key: usize) -> Option<&mut Client> {
-        self.clients.get_mut(&key)
+        let index = self.index(key)?;
+        self.slots[index].client.as_mut()
     }
 
-    // Subtract a specified duration from all client lifetimes
-    pub fn sub_duration(&mut self, d: Duration) {
-        self.clients.iter_mut().for_each(|(_, cl)|
-            cl.lifetime = cl.lifetime.saturating_sub(d)
-        );
-    }
+    // Keys of the clients whose deadline has passed. They're taken off the schedule, so each one
+    // has to be either rescheduled or removed.
+    pub fn expired(&mut self, now: Instant) -> Vec<usize> {
+        let mut keys = vec![];
 
-    // Returns a vec of "dead" clients (lifetimes that == 0)
-    pub fn remove_inactive(&mut self) -> Vec<Client> {
-        let mut dead = vec![];
-        let mut i = 0;
+        while let Some(&Reverse((deadline, key))) = self.deadlines.peek() {
+            if deadline > now {
+                break;
+            }
 
-        while i < self.lifetimes.len() {
-            let c = &self.lifetimes[i].clone();
+            self.deadlines.pop();
 
-            if self.clients[c].lifetime.is_zero() {
-                self.lifetimes.remove(i);
-                dead.push(self.clients.remove(&c).unwrap());
+            if self.is_scheduled(key, deadline) {
+                keys.push(key);
             }
-            else {
-                i += 1;
+        }
+
+        keys
+    }
+
+    // The soonest deadline of any client
+    pub fn next_deadline(&mut self) -> Option<Instant> {
+        while let Some(&Reverse((deadline, key))) = self.deadlines.peek() {
+            if self.is_scheduled(key, deadline) {
+                return Some(deadline);
             }
+
+            self.deadlines.pop();
+        }
+
+        None
+    }
+
+    // Move a client's deadline, after some activity or a change in what it's waiting for
+    pub fn reschedule(&mut self, key: usize, deadline: Instant) {
+        match self.get_mut(key) {
+            Some(client) if client.deadline != deadline => client.deadline = deadline,
+            _ => return
         }
 
-        dead
+        self.schedule(key, deadline);
     }
 
-    // Remove a specific client
+    // Old entries aren't looked for when a client is rescheduled or removed, they're skipped once
+    // they come up instead. They're cleared out all at once before they can outnumber the clients.
+    fn schedule(&mut self, key: usize, deadline: Instant) {
+        self.deadlines.push(Reverse((deadline, key)));
+
+        if self.deadlines.len() > 2 * self.len + 64 {
+            self.deadlines = self.slots.iter()
+                .filter_map(|slot| slot.client.as_ref())
+                .map(|client| Reverse((client.deadline, client.id)))
+                .collect();
+        }
+    }
+
+    // `false` for entries left behind by `schedule`
+    fn is_scheduled(&self, key: usize, deadline: Instant) -> bool {
+        self.get(key).is_some_and(|client| client.deadline == deadline)
+    }
+
+    // Remove a specific client, its slot is free to be reused under a new key
     pub fn remove(&mut self, key: &usize) -> Option<Client> {
-        match self.clients.remove(key) {
-            Some(client) => {
-                self.lifetimes.retain(|k| k != key);
-                Some(client)
-            },
-            None => None
+        let index = self.index(*key)?;
+        let slot = &mut self.slots[index];
+        let client = slot.client.take()?;
+        slot.generation = (slot.generation + 1) & GENERATION_MASK;
+
+        self.free.push(index);
+        self.len -= 1;
+        self.forget_topics(*key);
+
+        Some(client)
+    }
+
+    // Subscribe a client to a topic, ignored if the client has already been removed
+    pub fn subscribe(&mut self, key: usize, topic: &str) {
+        if self.get(key).is_some() {
+            self.topics.entry(topic.to_string()).or_default().insert(key);
         }
     }
 
-    // Returns the lowest lifetime
-    pub fn lowest_lifetime(&self) -> Option<Duration> {
-        let key = self.lifetimes.front()?;
-        Some(self.clients.get(key)?.lifetime)
+    pub fn unsubscribe(&mut self, key: usize, topic: &str) {
+        if let Some(subscribers) = self.topics.get_mut(topic) {
+            subscribers.remove(&key);
+
+            if subscribers.is_empty() {
+                self.topics.remove(topic);
+            }
+        }
+    }
+
+    // Keys of every client subscribed to a topic
+    pub fn subscribers(&self, topic: &str) -> Vec<usize> {
+        match self.topics.get(topic) {
+            Some(subscribers) => subscribers.iter().copied().collect(),
+            None => vec![]
+        }
+    }
+
+    // Drop a removed client from every topic it was subscribed to
+    fn forget_topics(&mut self, key: usize) {
+        self.topics.retain(|_, subscribers| {
+            subscribers.remove(&key);
+            !subscribers.is_empty()
+        });
     }
 }
 
 
 #[derive(Debug)]
 pub struct Client {
+    pub id: usize, // Key within `Clients`
     pub stream: TcpStream,
     pub address: SocketAddr,
-    pub lifetime: Duration
+    pub deadline: Instant, // Closed if there's no activity until then
+    pub phase: Phase,
+    pub buffer: Vec<u8>, // Received bytes that haven't been consumed as a request yet
+    pub parser: Parser,
+    pub requests: usize, // Number of requests received on this connection
+    pub responses: VecDeque<Encoder>, // Responses waiting to be written, in request order
+    pub websocket: Option<WebSocket>, // Set once the connection has been upgraded
+    pub event_stream: Option<EventStream>, // Set once the connection has been turned into an event stream
+    pub pending: VecDeque<u8>, // Output the socket wasn't ready to take yet, written from the front
+    pub interest: Event, // What the poller is currently watching the socket for
+    pub closing: bool, // Done with, closed once the remaining output has been written
+    pub redirect: bool, // Accepted on the redirect listener, requests are sent over to HTTPS
+    #[cfg(feature = "tls")]
+    pub tls: Option<rustls::ServerConnection> // Set if the server terminates TLS
 }
 
 impl Client {
-    pub fn new(stream: TcpStream, address: SocketAddr) -> Self {
+    pub fn new(id: usize, stream: TcpStream, address: SocketAddr, deadline: Instant) -> Self {
         Client {
+            id,
             stream,
             address,
-            lifetime: Duration::from_secs(5) // kill client connection after 5 secs inactivity
+            deadline,
+            phase: Phase::Idle,
+            buffer: vec![],
+            parser: Parser::default(),
+            requests: 0,
+            responses: VecDeque::new(),
+            websocket: None,
+            event_stream: None,
+            pending: VecDeque::new(),
+            interest: Event::readable(id),
+            closing: false,
+            redirect: false,
+            #[cfg(feature = "tls")]
+            tls: None
         }
     }
 
-    pub fn send(&mut self, response: Response) -> io::Result<()> {
-        self.stream.write_all(&response.try_into_bytes()?)
+    // Read whatever is available on the socket into the receive buffer, returns the number of
+    // bytes read (0 means the remote side closed the connection)
+    pub fn receive(&mut self) -> io::Result<usize> {
+        let mut chunk = [0u8; 2048];
+        let n = self.read(&mut chunk)?;
+
+        self.buffer.extend_from_slice(&chunk[0..n]);
+
+        #[cfg(feature = "tls")]
+        if let Some(tls) = self.tls.as_mut().filter(|_| n > 0) {
+            tls::read_buffered(tls, &mut self.buffer)?;
+        }
+
+        Ok(n)
+    }
+
+    // Queue a response to be written by `flush_output`
+    pub fn queue(&mut self, response: Response, version: u8, with_body: bool) {
+        self.responses.push_back(response.encode(version, with_body));
+    }
+
+    // Write as much output as the socket takes without blocking, returns the number of bytes
+    // written. Queued responses go first (oldest first), followed by any WebSocket frames or
+    // events, whatever's left waits in `pending` for the socket to become writable again.
+    pub fn flush_output(&mut self) -> io::Result<usize> {
+        let mut total = 0;
+
+        loop {
+            // Responses are encoded a block at a time as the socket takes them, so large files
+            // aren't read into memory all at once
+            if self.pending.is_empty() {
+                if let Some(encoder) = self.responses.front_mut() {
+                    match encoder.next() {
+                        Some(block) => self.pending = block?.into(),
+                        None => {
+                            self.responses.pop_front();
+                        }
+                    }
+
+                    continue;
+                }
+            }
+
+            if self.responses.is_empty() {
+                if let Some(websocket) = &mut self.websocket {
+                    self.pending.extend(websocket.take_output());
+                }
+
+                if let Some(stream) = &mut self.event_stream {
+                    self.pending.extend(stream.take_output());
+                }
+            }
+
+            if self.pending.is_empty() {
+                break;
+            }
+
+            let written = self.write_pending()?;
+            total += written;
+
+            if !self.pending.is_empty() {
+                break;
+            }
+        }
+
+        match self.flush() {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(total),
+            result => result.map(|_| total)
+        }
+    }
+
+    // Write the front of `pending` until it's empty or the socket would block. Taking written
+    // bytes off the front doesn't move the rest, which can be megabytes for a slow subscriber.
+    fn write_pending(&mut self) -> io::Result<usize> {
+        let mut pending = std::mem::take(&mut self.pending);
+        let mut written = 0;
+
+        let result = loop {
+            let (front, _) = pending.as_slices();
+
+            if front.is_empty() {
+                break Ok(written);
+            }
+
+            match self.write(front) {
+                Ok(0) => break Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    pending.drain(..n);
+                    written += n;
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(written),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => break Err(e)
+            }
+        };
+
+        self.pending = pending;
+
+        if written > 0 && matches!(self.phase, Phase::Writing(_)) {
+            self.phase = Phase::Writing(Instant::now());
+        }
+
+        result
+    }
+
+    // Work out what the connection is waiting on, a request that's still arriving keeps the time
+    // it started at however slowly it trickles in. So does a TLS handshake, which comes first.
+    pub fn update_phase(&mut self, now: Instant) -> Phase {
+        let in_body = self.parser.body_received().is_some();
+        let in_head = !self.buffer.is_empty() || self.is_handshaking();
+
+        self.phase = match self.phase {
+            Phase::Writing(since) if self.has_pending_output() => Phase::Writing(since),
+            _ if self.has_pending_output() => Phase::Writing(now),
+            Phase::Body(start) if in_body => Phase::Body(start),
+            _ if in_body => Phase::Body(now),
+            Phase::Head(start) if in_head => Phase::Head(start),
+            _ if in_head => Phase::Head(now),
+            _ => Phase::Idle
+        };
+
+        self.phase
+    }
+
+    // `true` until the TLS handshake is done, if TLS is used at all
+    pub fn is_handshaking(&self) -> bool {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            return tls.is_handshaking();
+        }
+
+        false
+    }
+
+    // `true` while there's output the socket hasn't taken yet
+    pub fn has_pending_output(&self) -> bool {
+        #[cfg(feature = "tls")]
+        if self.tls.as_ref().is_some_and(|tls| tls.wants_write()) {
+            return true;
+        }
+
+        !self.responses.is_empty() || !self.pending.is_empty()
+    }
+
+    // Let a TLS peer know the connection is being closed on purpose, best effort
+    pub fn shutdown(&mut self) {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &mut self.tls {
+            tls.send_close_notify();
+            let _ = tls::flush(tls, &mut self.stream);
+        }
     }
 }
 
+// Reads & writes go through TLS when it's in use
 impl io::Read for Client {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &mut self.tls {
+            return tls::read(tls, &mut self.stream, buf);
+        }
+
         self.stream.read(buf)
     }
 }
 
 impl io::Write for Client {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &mut self.tls {
+            return tls::write(tls, &mut self.stream, buf);
+        }
+
         self.stream.write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &mut self.tls {
+            return tls::flush(tls, &mut self.stream);
+        }
+
         self.stream.flush()
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        io::Read,
+        net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+        time::{Duration, Instant}
+    };
+
+    use super::{Client, Clients, GENERATION_MASK};
+
+
+    // A connected socket, standing in for an accepted client
+    fn connection(listener: &TcpListener) -> (TcpStream, SocketAddr) {
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        listener.accept().unwrap()
+    }
+
+
+    #[test]
+    fn keys_are_unique_and_never_zero() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut clients = Clients::new();
+        let mut keys = HashSet::new();
+
+        for _ in 0..100 {
+            let (stream, address) = connection(&listener);
+            let (key, client) = clients.add(stream, address, Duration::from_secs(5)).unwrap();
+
+            assert_eq!(client.address, address);
+            assert_ne!(key, 0);
+            assert!(keys.insert(key));
+        }
+
+        assert_eq!(clients.len(), 100);
+
+        for key in keys {
+            assert_eq!(clients.get(key).unwrap().id, key);
+        }
+    }
+
+    #[test]
+    fn reused_slots_get_new_keys() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut clients = Clients::new();
+
+        let (stream, address) = connection(&listener);
+        let (old, _) = clients.add(stream, address, Duration::from_secs(5)).unwrap();
+        clients.subscribe(old, "news");
+        assert!(clients.remove(&old).is_some());
+
+        let (stream, address) = connection(&listener);
+        let (new, _) = clients.add(stream, address, Duration::from_secs(5)).unwrap();
+
+        // Same slot, but events & subscriptions for the old key can't reach the new client
+        assert_eq!(clients.slots.len(), 1);
+        assert_ne!(old, new);
+        assert!(clients.get(old).is_none());
+        assert!(clients.get_mut(old).is_none());
+        assert!(clients.remove(&old).is_none());
+        assert!(clients.subscribers("news").is_empty());
+        clients.reschedule(old, Instant::now());
+
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients.get(new).unwrap().address, address);
+        assert_eq!(clients.next_deadline(), Some(clients.get(new).unwrap().deadline));
+    }
+
+    #[test]
+    fn generations_wrap_below_reserved_keys() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut clients = Clients::new();
+
+        let (stream, address) = connection(&listener);
+        let (first, _) = clients.add(stream, address, Duration::from_secs(5)).unwrap();
+        clients.remove(&first);
+        clients.slots[0].generation = GENERATION_MASK;
+
+        let (stream, address) = connection(&listener);
+        let (last, _) = clients.add(stream, address, Duration::from_secs(5)).unwrap();
+        assert!(last < usize::MAX / 2);
+        clients.remove(&last);
+
+        let (stream, address) = connection(&listener);
+        let (wrapped, _) = clients.add(stream, address, Duration::from_secs(5)).unwrap();
+        assert_eq!(wrapped, 1);
+        assert!(clients.get(last).is_none());
+    }
+
+    #[test]
+    fn expires_clients_in_deadline_order() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut clients = Clients::new();
+        let mut add = |timeout| {
+            let (stream, address) = connection(&listener);
+            clients.add(stream, address, Duration::from_secs(timeout)).unwrap().0
+        };
+
+        let long = add(300);
+        let short = add(5);
+        let medium = add(15);
+        let start = clients.get(long).unwrap().deadline - Duration::from_secs(300);
+
+        assert_eq!(clients.next_deadline(), Some(clients.get(short).unwrap().deadline));
+        assert!(clients.expired(start).is_empty());
+
+        let expired = clients.expired(start + Duration::from_secs(20));
+        assert_eq!(expired, [short, medium]);
+
+        // Expired clients are off the schedule until they're rescheduled
+        assert!(clients.expired(start + Duration::from_secs(20)).is_empty());
+        assert_eq!(clients.next_deadline(), Some(clients.get(long).unwrap().deadline));
+    }
+
+    #[test]
+    fn rescheduling_replaces_deadlines() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut clients = Clients::new();
+
+        let (stream, address) = connection(&listener);
+        let (key, _) = clients.add(stream, address, Duration::from_secs(5)).unwrap();
+        let old = clients.get(key).unwrap().deadline;
+
+        // Old entries are skipped, and don't pile up however often a client is rescheduled
+        for i in 0..1000 {
+            clients.reschedule(key, old + Duration::from_secs(60) + Duration::from_millis(i));
+        }
+
+        assert!(clients.deadlines.len() <= 66);
+        assert!(clients.next_deadline().unwrap() > old + Duration::from_secs(50));
+        assert!(clients.expired(old + Duration::from_secs(10)).is_empty());
+
+        let removed = clients.remove(&key).unwrap();
+        assert!(clients.next_deadline().is_none());
+        assert!(clients.expired(removed.deadline + Duration::from_secs(1)).is_empty());
+    }
+
+    #[test]
+    fn writes_queued_output_in_order_across_partial_writes() {
+        const HALF: usize = 8 * 1024 * 1024;
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut reader = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, address) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+
+        let data: Vec<u8> = (0..2 * HALF).map(|i| (i % 251) as u8).collect();
+        let mut client = Client::new(1, stream, address, Instant::now());
+
+        // More is queued behind what the socket didn't take, like frames for a slow subscriber
+        client.pending.extend(&data[..HALF]);
+        assert!(client.flush_output().unwrap() < HALF);
+        client.pending.extend(&data[HALF..]);
+
+        let mut received = vec![];
+        let mut buffer = vec![0; 64 * 1024];
+
+        while received.len() < data.len() {
+            client.flush_output().unwrap();
+
+            let len = reader.read(&mut buffer).unwrap();
+            received.extend_from_slice(&buffer[..len]);
+        }
+
+        assert!(client.pending.is_empty());
+        assert!(received == data);
+    }
+}
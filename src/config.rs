@@ -1,9 +1,9 @@
-use std::{net::{IpAddr, Ipv6Addr}, path::PathBuf, str::FromStr};
+use std::{collections::HashMap, net::{IpAddr, Ipv6Addr}, path::PathBuf, str::FromStr, time::Duration};
 
 use crate::logging::LogLevel;
 
 
-const HELP_MSG: &'static str = include_str!("./help.msg");
+const HELP_MSG: &str = include_str!("./help.msg");
 
 
 #[derive(Debug)]
@@ -50,6 +50,12 @@ impl From<std::net::AddrParseError> for Error {
     }
 }
 
+impl From<std::num::ParseFloatError> for Error {
+    fn from(value: std::num::ParseFloatError) -> Self {
+        Self::new(ErrorKind::BadArg, value.to_string())
+    }
+}
+
 impl From<std::num::ParseIntError> for Error {
     fn from(value: std::num::ParseIntError) -> Self {
         Self::new(ErrorKind::BadArg, value.to_string())
@@ -61,7 +67,36 @@ pub struct Config {
     pub ip: IpAddr,
     pub port: u16,
     pub directory: PathBuf,
-    pub log_level: LogLevel
+    pub follow_symlinks: bool, // Serve symlinks that point outside of `directory`
+    #[allow(dead_code)]
+    pub log_level: LogLevel,
+    pub max_head_size: usize, // Largest request head (request line + headers) we'll buffer, in bytes
+    pub max_body_size: usize, // Largest request body we'll buffer, in bytes
+    pub keep_alive_timeout: Duration, // How long an idle connection is kept open
+    pub header_timeout: Duration, // How long a request head can take to arrive, from its first byte
+    pub body_timeout: Duration, // How long a request body can take to arrive, on top of what `min_body_rate` allows for its size
+    pub min_body_rate: usize, // Slowest a request body is allowed to arrive in bytes per second, with 0 `body_timeout` is only the longest pause
+    pub write_timeout: Duration, // How long a response can go without the client reading any of it
+    pub max_requests: usize, // Requests served on one connection before it's closed
+    pub websocket_timeout: Duration, // How long an idle WebSocket connection is kept open
+    pub max_message_size: usize, // Largest WebSocket message we'll buffer, in bytes
+    pub websocket_deflate: bool, // Accept permessage-deflate compression
+    pub deflate_no_context_takeover: bool, // Compress each message on its own, saves memory at the cost of ratio
+    pub max_send_queue: usize, // Unsent WebSocket/event stream output allowed to pile up before a client is dropped as too slow, in bytes
+    pub sse_keepalive: Duration, // How often idle event streams are sent a comment
+    pub sse_history: usize, // Events kept per topic for clients reconnecting with a Last-Event-ID
+    pub tls_cert: Option<PathBuf>, // PEM certificate chain, TLS is used if this & `tls_key` are set
+    pub tls_key: Option<PathBuf>, // PEM private key
+    pub tls_hosts: HashMap<String, (PathBuf, PathBuf)>, // Certificate chain & key for each SNI hostname ("*.example.com" for wildcards), the above is the fallback
+    pub tls_reload_interval: Duration, // How often certificate & key files are checked for changes, zero to never reload them
+    pub redirect_port: Option<u16>, // Plaintext port that redirects everything to HTTPS
+    pub redirect_exempt: Vec<String>, // Path prefixes served on the redirect port instead of being redirected
+    pub workers: usize, // Event loops sharing the connections, each on its own thread
+    pub max_connections: usize, // Open connections across all workers, new ones are turned away with a 503 past this
+    pub max_connections_per_ip: usize, // Open connections from one client (see `ipv6_prefix`), 0 for no limit
+    pub rate_limit: f64, // Requests per second allowed from one client, 0 for no limit
+    pub rate_limit_burst: u32, // Requests a client can make at once before `rate_limit` kicks in
+    pub ipv6_prefix: u8 // IPv6 addresses sharing this many leading bits count as the same client
 }
 
 impl Default for Config {
@@ -70,7 +105,35 @@ impl Default for Config {
             ip: IpAddr::V6(Ipv6Addr::LOCALHOST),
             port: 8080,
             directory: PathBuf::from("."),
-            log_level: LogLevel::Warning
+            follow_symlinks: false,
+            log_level: LogLevel::Warning,
+            max_head_size: 16 * 1024,
+            max_body_size: 8 * 1024 * 1024,
+            keep_alive_timeout: Duration::from_secs(5),
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(20),
+            min_body_rate: 500,
+            write_timeout: Duration::from_secs(30),
+            max_requests: 100,
+            websocket_timeout: Duration::from_secs(300),
+            max_message_size: 16 * 1024 * 1024,
+            websocket_deflate: true,
+            deflate_no_context_takeover: false,
+            max_send_queue: 4 * 1024 * 1024,
+            sse_keepalive: Duration::from_secs(15),
+            sse_history: 100,
+            tls_cert: None,
+            tls_key: None,
+            tls_hosts: HashMap::new(),
+            tls_reload_interval: Duration::from_secs(60),
+            redirect_port: None,
+            redirect_exempt: vec![String::from("/.well-known/acme-challenge/")],
+            workers: std::thread::available_parallelism().map_or(1, usize::from),
+            max_connections: 10_000,
+            max_connections_per_ip: 0,
+            rate_limit: 0.0,
+            rate_limit_burst: 20,
+            ipv6_prefix: 64
         }
     }
 }
@@ -102,6 +165,150 @@ pub fn load_config() -> Result<Config, Error> {
                 cfg.directory = PathBuf::from(dir);
             },
 
+            "--follow-symlinks" => cfg.follow_symlinks = true,
+
+            "--max-header-size" => {
+                let size = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --max-header-size"))?;
+                cfg.max_head_size = size.parse()?;
+            },
+
+            "--max-body-size" => {
+                let size = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --max-body-size"))?;
+                cfg.max_body_size = size.parse()?;
+            },
+
+            "--keep-alive" => {
+                let secs = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --keep-alive"))?;
+                cfg.keep_alive_timeout = Duration::from_secs(secs.parse()?);
+            },
+
+            "--header-timeout" => {
+                let secs = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --header-timeout"))?;
+                cfg.header_timeout = Duration::from_secs(secs.parse()?);
+            },
+
+            "--body-timeout" => {
+                let secs = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --body-timeout"))?;
+                cfg.body_timeout = Duration::from_secs(secs.parse()?);
+            },
+
+            "--min-body-rate" => {
+                let rate = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --min-body-rate"))?;
+                cfg.min_body_rate = rate.parse()?;
+            },
+
+            "--write-timeout" => {
+                let secs = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --write-timeout"))?;
+                cfg.write_timeout = Duration::from_secs(secs.parse()?);
+            },
+
+            "--max-requests" => {
+                let max = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --max-requests"))?;
+                cfg.max_requests = max.parse()?;
+            },
+
+            "--websocket-timeout" => {
+                let secs = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --websocket-timeout"))?;
+                cfg.websocket_timeout = Duration::from_secs(secs.parse()?);
+            },
+
+            "--max-message-size" => {
+                let size = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --max-message-size"))?;
+                cfg.max_message_size = size.parse()?;
+            },
+
+            "--max-send-queue" => {
+                let size = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --max-send-queue"))?;
+                cfg.max_send_queue = size.parse()?;
+            },
+
+            "--sse-keepalive" => {
+                let secs = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --sse-keepalive"))?;
+                cfg.sse_keepalive = Duration::from_secs(secs.parse()?);
+            },
+
+            "--sse-history" => {
+                let count = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --sse-history"))?;
+                cfg.sse_history = count.parse()?;
+            },
+
+            "--tls-cert" => {
+                let path = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --tls-cert"))?;
+                cfg.tls_cert = Some(PathBuf::from(path));
+            },
+
+            "--tls-key" => {
+                let path = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --tls-key"))?;
+                cfg.tls_key = Some(PathBuf::from(path));
+            },
+
+            "--tls-host" => {
+                let host = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing hostname for --tls-host"))?;
+                let cert = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing certificate for --tls-host"))?;
+                let key = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing private key for --tls-host"))?;
+                cfg.tls_hosts.insert(host.to_ascii_lowercase(), (PathBuf::from(cert), PathBuf::from(key)));
+            },
+
+            "--tls-reload" => {
+                let secs = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --tls-reload"))?;
+                cfg.tls_reload_interval = Duration::from_secs(secs.parse()?);
+            },
+
+            "--redirect-port" => {
+                let port = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --redirect-port"))?;
+                cfg.redirect_port = Some(port.parse()?);
+            },
+
+            "--redirect-exempt" => {
+                let path = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --redirect-exempt"))?;
+                cfg.redirect_exempt.push(path);
+            },
+
+            "--workers" => {
+                let count = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --workers"))?;
+                cfg.workers = count.parse()?;
+
+                if cfg.workers == 0 {
+                    return Err(Error::new(ErrorKind::BadArg, "--workers needs to be at least 1"));
+                }
+            },
+
+            "--max-connections" => {
+                let max = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --max-connections"))?;
+                cfg.max_connections = max.parse()?;
+            },
+
+            "--max-connections-per-ip" => {
+                let max = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --max-connections-per-ip"))?;
+                cfg.max_connections_per_ip = max.parse()?;
+            },
+
+            "--rate-limit" => {
+                let rate = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --rate-limit"))?;
+                cfg.rate_limit = rate.parse()?;
+
+                if !cfg.rate_limit.is_finite() || cfg.rate_limit < 0.0 {
+                    return Err(Error::new(ErrorKind::BadArg, "--rate-limit can't be negative"));
+                }
+            },
+
+            "--rate-burst" => {
+                let count = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --rate-burst"))?;
+                cfg.rate_limit_burst = count.parse()?;
+            },
+
+            "--ipv6-prefix" => {
+                let bits = args.next().ok_or(Error::new(ErrorKind::MissingArg, "Missing argument for --ipv6-prefix"))?;
+                cfg.ipv6_prefix = bits.parse()?;
+
+                if cfg.ipv6_prefix > 128 {
+                    return Err(Error::new(ErrorKind::BadArg, "--ipv6-prefix can't be longer than 128 bits"));
+                }
+            },
+
+            "--no-deflate" => cfg.websocket_deflate = false,
+            "--deflate-no-context" => cfg.deflate_no_context_takeover = true,
+
             _ => return Err(Error::new(ErrorKind::UnknownOption, format!("Unknown option: \"{}\"", arg)))
         }
     }
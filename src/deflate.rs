@@ -0,0 +1,349 @@
+// The permessage-deflate WebSocket extension (RFC 7692)
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use crate::websocket::{CLOSE_INVALID_DATA, CLOSE_TOO_BIG};
+
+
+// Every compressed message ends with an empty deflate block, which is left off on the wire
+const TAIL: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+
+// Parameters agreed on during the handshake
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Params {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    pub server_max_window_bits: Option<u8>,
+    pub client_max_window_bits: Option<u8> // Only sent back if the client offered it
+}
+
+impl Params {
+    // Parse a single permessage-deflate offer (without the extension name), `None` if it has
+    // unknown, repeated or invalid parameters
+    fn parse(offer: &str) -> Option<Self> {
+        let mut params = Params {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            server_max_window_bits: None,
+            client_max_window_bits: None
+        };
+
+        let mut client_window_offered = false;
+
+        for param in offer.split(';').map(str::trim).filter(|param| !param.is_empty()) {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None)
+            };
+
+            match (name, value) {
+                ("server_no_context_takeover", None) if !params.server_no_context_takeover => {
+                    params.server_no_context_takeover = true;
+                },
+                ("client_no_context_takeover", None) if !params.client_no_context_takeover => {
+                    params.client_no_context_takeover = true;
+                },
+                ("server_max_window_bits", Some(bits)) if params.server_max_window_bits.is_none() => {
+                    params.server_max_window_bits = Some(window_bits(bits)?);
+                },
+                // The client may offer this without a value, just to say it understands it
+                ("client_max_window_bits", bits) if !client_window_offered => {
+                    client_window_offered = true;
+                    params.client_max_window_bits = match bits {
+                        Some(bits) => Some(window_bits(bits)?),
+                        None => None
+                    };
+                },
+                _ => return None
+            }
+        }
+
+        // zlib can't compress with a 256 byte window, so that's the one offer we can't take
+        if params.server_max_window_bits == Some(8) {
+            return None;
+        }
+
+        Some(params)
+    }
+
+    // The Sec-WebSocket-Extensions value accepting these parameters
+    fn response(&self) -> String {
+        let mut response = String::from("permessage-deflate");
+
+        if self.server_no_context_takeover {
+            response.push_str("; server_no_context_takeover");
+        }
+
+        if self.client_no_context_takeover {
+            response.push_str("; client_no_context_takeover");
+        }
+
+        if let Some(bits) = self.server_max_window_bits {
+            response.push_str(&format!("; server_max_window_bits={}", bits));
+        }
+
+        if let Some(bits) = self.client_max_window_bits {
+            response.push_str(&format!("; client_max_window_bits={}", bits));
+        }
+
+        response
+    }
+}
+
+
+// Parse a window size parameter, which must be a plain number between 8 and 15
+fn window_bits(value: &str) -> Option<u8> {
+    match value.parse() {
+        Ok(bits @ 8..=15) if value.bytes().all(|b| b.is_ascii_digit()) => Some(bits),
+        _ => None
+    }
+}
+
+
+// Pick the first acceptable permessage-deflate offer from a Sec-WebSocket-Extensions header,
+// returning the negotiated state along with the header value to answer with.
+// `no_context_takeover` forces the server to compress every message on its own.
+pub fn negotiate(extensions: &str, no_context_takeover: bool) -> Option<(Deflate, String)> {
+    let mut params = extensions.split(',')
+        .filter_map(|offer| {
+            let (name, params) = offer.split_once(';').unwrap_or((offer, ""));
+            (name.trim() == "permessage-deflate").then_some(params)
+        })
+        .find_map(Params::parse)?;
+
+    params.server_no_context_takeover |= no_context_takeover;
+
+    let response = params.response();
+    Some((Deflate::new(params), response))
+}
+
+
+// Compression state for a single WebSocket connection
+pub struct Deflate {
+    params: Params,
+    compress: Compress,
+    decompress: Decompress
+}
+
+impl std::fmt::Debug for Deflate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Deflate").field("params", &self.params).finish()
+    }
+}
+
+impl Deflate {
+    pub fn new(params: Params) -> Self {
+        let window = params.server_max_window_bits.unwrap_or(15);
+
+        Deflate {
+            compress: Compress::new_with_window_bits(Compression::default(), false, window),
+            // Decompressing with the largest window works whatever the client compressed with
+            decompress: Decompress::new_with_window_bits(false, 15),
+            params
+        }
+    }
+
+    // Compress an outgoing message payload
+    pub fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, flate2::CompressError> {
+        let mut output = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.compress.total_in();
+
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity());
+            }
+
+            self.compress.compress_vec(&data[consumed..], &mut output, FlushCompress::Sync)?;
+
+            // A sync flush is done once all input is used and there's output space to spare
+            if self.compress.total_in() - start == data.len() as u64 && output.len() < output.capacity() {
+                break;
+            }
+        }
+
+        if output.ends_with(&TAIL) {
+            output.truncate(output.len() - TAIL.len());
+        }
+
+        if self.params.server_no_context_takeover {
+            self.compress.reset();
+        }
+
+        Ok(output)
+    }
+
+    // Decompress an incoming message payload, errors hold the close code & reason to fail the
+    // connection with
+    pub fn decompress(&mut self, data: &[u8], max_size: usize) -> Result<Vec<u8>, (u16, &'static str)> {
+        let invalid = (CLOSE_INVALID_DATA, "Invalid compressed data");
+        let too_big = (CLOSE_TOO_BIG, "Message too big");
+
+        let mut input = Vec::with_capacity(data.len() + TAIL.len());
+        input.extend_from_slice(data);
+        input.extend_from_slice(&TAIL);
+
+        let mut output = Vec::with_capacity((data.len() * 2).min(max_size + 1).max(64));
+        let start = self.decompress.total_in();
+
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let written = output.len();
+
+            if output.len() == output.capacity() {
+                if output.len() > max_size {
+                    return Err(too_big);
+                }
+
+                output.reserve(output.capacity().min(max_size + 1 - output.len()).max(64));
+            }
+
+            let status = self.decompress.decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync).map_err(|_| invalid)?;
+
+            let done = self.decompress.total_in() - start == input.len() as u64 && output.len() < output.capacity();
+            let stuck = self.decompress.total_in() - start == consumed as u64 && output.len() == written;
+
+            match status {
+                Status::StreamEnd => break,
+                _ if done => break,
+                _ if stuck => return Err(invalid),
+                _ => ()
+            }
+        }
+
+        if output.len() > max_size {
+            return Err(too_big);
+        }
+
+        if self.params.client_no_context_takeover {
+            self.decompress.reset(false);
+        }
+
+        Ok(output)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{negotiate, Deflate, Params};
+    use crate::websocket::{CLOSE_INVALID_DATA, CLOSE_TOO_BIG};
+
+
+    fn params(server_no_context_takeover: bool, client_no_context_takeover: bool) -> Params {
+        Params {
+            server_no_context_takeover,
+            client_no_context_takeover,
+            server_max_window_bits: None,
+            client_max_window_bits: None
+        }
+    }
+
+
+    #[test]
+    fn parses_offers() {
+        assert_eq!(Params::parse(""), Some(params(false, false)));
+        assert_eq!(Params::parse(" server_no_context_takeover ; client_no_context_takeover"), Some(params(true, true)));
+
+        // The client's window size can come with or without a value
+        let offered = Params::parse("client_max_window_bits").unwrap();
+        assert_eq!(offered.client_max_window_bits, None);
+
+        let limited = Params::parse("client_max_window_bits=10; server_max_window_bits=\"12\"").unwrap();
+        assert_eq!(limited.client_max_window_bits, Some(10));
+        assert_eq!(limited.server_max_window_bits, Some(12));
+    }
+
+    #[test]
+    fn rejects_invalid_offers() {
+        for offer in [
+            "server_max_window_bits=7",
+            "server_max_window_bits=16",
+            "server_max_window_bits=+9",
+            "server_max_window_bits",
+            "client_max_window_bits=ten",
+            "server_no_context_takeover=1",
+            "server_no_context_takeover; server_no_context_takeover",
+            "client_max_window_bits; client_max_window_bits=10",
+            "unknown_param",
+            // zlib can't do a 256 byte window
+            "server_max_window_bits=8"
+        ] {
+            assert_eq!(Params::parse(offer), None, "{:?}", offer);
+        }
+    }
+
+    #[test]
+    fn negotiates_the_first_acceptable_offer() {
+        let (_, response) = negotiate("permessage-deflate; client_max_window_bits", false).unwrap();
+        assert_eq!(response, "permessage-deflate");
+
+        // Offers we can't take are skipped, as are other extensions
+        let extensions = "x-webkit-deflate-frame, permessage-deflate; server_max_window_bits=8, permessage-deflate; server_no_context_takeover; client_max_window_bits=9";
+        let (_, response) = negotiate(extensions, false).unwrap();
+        assert_eq!(response, "permessage-deflate; server_no_context_takeover; client_max_window_bits=9");
+
+        // The server can insist on compressing messages on their own
+        let (_, response) = negotiate("permessage-deflate", true).unwrap();
+        assert_eq!(response, "permessage-deflate; server_no_context_takeover");
+
+        assert!(negotiate("permessage-deflate; bogus", false).is_none());
+        assert!(negotiate("x-webkit-deflate-frame", false).is_none());
+        assert!(negotiate("", false).is_none());
+    }
+
+    #[test]
+    fn round_trips_with_context_takeover() {
+        let mut server = Deflate::new(params(false, false));
+        let mut client = Deflate::new(params(false, false));
+        let message = "The quick brown fox jumps over the lazy dog. ".repeat(20);
+
+        let first = server.compress(message.as_bytes()).unwrap();
+        let second = server.compress(message.as_bytes()).unwrap();
+
+        // The second one refers back to the first
+        assert!(first.len() < message.len() / 4);
+        assert!(second.len() < first.len());
+
+        assert_eq!(client.decompress(&first, 1024).unwrap(), message.as_bytes());
+        assert_eq!(client.decompress(&second, 1024).unwrap(), message.as_bytes());
+    }
+
+    #[test]
+    fn round_trips_without_context_takeover() {
+        let mut server = Deflate::new(params(true, true));
+        let mut client = Deflate::new(params(true, true));
+        let message = "The quick brown fox jumps over the lazy dog. ".repeat(20);
+
+        let first = server.compress(message.as_bytes()).unwrap();
+        let second = server.compress(message.as_bytes()).unwrap();
+
+        // Each message stands on its own, so they can be decompressed in any order
+        assert_eq!(first, second);
+        assert_eq!(client.decompress(&second, 1024).unwrap(), message.as_bytes());
+        assert_eq!(client.decompress(&first, 1024).unwrap(), message.as_bytes());
+
+        assert_eq!(client.decompress(&server.compress(b"").unwrap(), 1024).unwrap(), b"");
+    }
+
+    #[test]
+    fn limits_decompressed_size() {
+        let mut server = Deflate::new(params(true, true));
+        let mut client = Deflate::new(params(true, true));
+
+        // Compresses down to almost nothing
+        let bomb = server.compress(&[0; 100_000]).unwrap();
+        assert!(bomb.len() < 1000);
+
+        assert_eq!(client.decompress(&bomb, 99_999).unwrap_err().0, CLOSE_TOO_BIG);
+        assert_eq!(client.decompress(&bomb, 100_000).unwrap().len(), 100_000);
+    }
+
+    #[test]
+    fn rejects_invalid_data() {
+        let mut client = Deflate::new(params(false, false));
+        assert_eq!(client.decompress(&[0xFF, 0xFF, 0xFF], 1024).unwrap_err().0, CLOSE_INVALID_DATA);
+    }
+}
@@ -1,151 +1,312 @@
-use std::{io::{self, Read}, net::{IpAddr, SocketAddr, TcpListener}, rc::Rc, time::Instant};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{self, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{atomic::{AtomicUsize, Ordering}, mpsc::{self, Receiver, Sender}, Arc, OnceLock},
+    thread,
+    time::{Duration, Instant}
+};
 
-use httparse::{Request, EMPTY_HEADER};
 use polling::{Event, PollMode, Poller};
 
-use crate::{client::Clients, config::Config, log, logging::{LogLevel, Logger}, response::{Status, Response}};
+use crate::{
+    client::{Client, Clients, Phase},
+    config::Config,
+    log,
+    logging::{LogLevel, Logger},
+    ratelimit::RateLimiter,
+    redirect,
+    request::{Method, Request},
+    response::{Body, Builder, Response, Status},
+    sse::{self, EventStream, History},
+    websocket::{self, Action, Message, WebSocket}
+};
+#[cfg(feature = "tls")]
+use crate::tls;
+
+
+// Poller key of the redirect listener, client keys never get this high (see `Clients`)
+const REDIRECT_KEY: usize = usize::MAX - 1;
+
+// Errors for running out of file descriptors, per process & system wide (the same on Linux, macOS
+// and the BSDs)
+const EMFILE: i32 = 24;
+const ENFILE: i32 = 23;
+
+// Opened ahead of time, so there's a file descriptor to spare for turning connections away
+const RESERVE_PATH: &str = if cfg!(windows) { "NUL" } else { "/dev/null" };
+
+// How long clients turned away for being over the connection limit are asked to wait
+const RETRY_AFTER: Duration = Duration::from_secs(5);
+
+// How long connections that were turned away are kept open after their response, closing them
+// with a request still unread would reset them and could take the response with it
+const LINGER: Duration = Duration::from_secs(1);
+
+// Turned away connections lingering at once, past this the oldest ones are closed early
+const MAX_LINGERING: usize = 64;
 
 
 pub struct Server {
     listener: TcpListener,
-    clients: Clients,
-    config: Rc<Config>,
-    poller: Poller,
+    redirect_listener: Option<TcpListener>, // Plaintext listener redirecting to HTTPS
+    config: Arc<Config>,
     logger: Logger,
+    websocket: Option<Box<websocket::Handler>>,
+    publisher: Publisher, // Starts reaching the event loops once `listen` has set them up
+    connections: Arc<AtomicUsize>, // Open connections across all event loops
+    limiter: Arc<RateLimiter>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>> // Set if connections are wrapped in TLS
 }
 
 impl Server {
     pub fn bind(address: IpAddr, port: u16, logger: Logger) -> io::Result<Self> {
+        let config = Config::default();
+
+        // Accepted from until there's nothing left in the backlog, without blocking
+        let listener = TcpListener::bind(SocketAddr::new(address, port))?;
+        listener.set_nonblocking(true)?;
+
         Ok(Server {
-            listener: TcpListener::bind(SocketAddr::new(address, port))?,
-            clients: Clients::new(),
-            config: Rc::new(Config::default()),
-            poller: Poller::new()?,
-            logger
+            listener,
+            redirect_listener: None,
+            limiter: Arc::new(RateLimiter::new(&config)),
+            config: Arc::new(config),
+            logger,
+            websocket: None,
+            publisher: Publisher { loops: Arc::new(OnceLock::new()) },
+            connections: Arc::new(AtomicUsize::new(0)),
+            #[cfg(feature = "tls")]
+            tls: None
         })
     }
 
-    pub fn with_config(mut self, config: Config) -> Self {
-        self.config = Rc::new(config);
+    // Use `config`, which fails if its TLS certificates & keys can't be loaded
+    pub fn with_config(mut self, config: Config) -> io::Result<Self> {
+        if config.tls_cert.is_some() != config.tls_key.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "TLS needs both a certificate and a private key"));
+        }
+
+        #[cfg(feature = "tls")]
+        {
+            self.tls = tls::server_config(&config)?;
+        }
+
+        #[cfg(not(feature = "tls"))]
+        if config.tls_cert.is_some() || !config.tls_hosts.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Built without TLS support, enable the \"tls\" feature"));
+        }
+
+        if let Some(port) = config.redirect_port {
+            if config.tls_cert.is_none() && config.tls_hosts.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Redirecting to HTTPS needs TLS to be set up"));
+            }
+
+            let listener = TcpListener::bind(SocketAddr::new(config.ip, port))?;
+            listener.set_nonblocking(true)?;
+            self.redirect_listener = Some(listener);
+        }
+
+        self.limiter = Arc::new(RateLimiter::new(&config));
+        self.config = Arc::new(config);
+        Ok(self)
+    }
+
+    #[allow(dead_code)]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // The address of the plaintext listener redirecting to HTTPS, if there is one
+    pub fn redirect_addr(&self) -> Option<io::Result<SocketAddr>> {
+        self.redirect_listener.as_ref().map(TcpListener::local_addr)
+    }
+
+    // Accept WebSocket upgrade requests, handing their connections to `handler`
+    pub fn with_websocket<W>(mut self, handler: W) -> Self
+        where W: Fn(&mut WebSocket, websocket::Event, Arc<Config>, Logger) + Send + Sync + 'static
+    {
+        self.websocket = Some(Box::new(handler));
         self
     }
 
-    pub fn listen<F: Fn(httparse::Request, Rc<Config>, Logger) -> Response>(mut self, cb: F) {
-        if let Err(e) = self.poller.add_with_mode(&self.listener, Event::readable(0), PollMode::Level) {
-            log!(self.logger, LogLevel::Error, "Error adding TcpListener to Poller: {}", e);
+    // A handle for publishing to WebSocket & event stream topics from outside of the handlers
+    pub fn publisher(&self) -> Publisher {
+        self.publisher.clone()
+    }
+
+    // Run `config.workers` event loops, this thread becomes the one accepting connections, which
+    // it hands out to all of them in turn. Each connection stays with the loop it was handed to,
+    // so its requests are still answered in order.
+    pub fn listen<F>(self, cb: F)
+        where F: Fn(Request, Arc<Config>, Logger) -> Response + Sync
+    {
+        let mut loops = match self.event_loops() {
+            Ok(loops) => loops,
+            Err(e) => {
+                log!(self.logger, LogLevel::Error, "Error starting event loops: {}", e);
+                return;
+            }
+        };
+
+        let main_loop = loops.pop().expect("At least one event loop");
+        let cb = &cb;
+
+        thread::scope(|scope| {
+            for event_loop in loops {
+                scope.spawn(move || event_loop.run(cb));
+            }
+
+            main_loop.run(cb);
+        });
+    }
+
+    // Set up an event loop for each worker, the last one is the acceptor. Only it waits on the
+    // listeners, so a new connection doesn't wake up every loop.
+    fn event_loops(&self) -> io::Result<Vec<EventLoop<'_>>> {
+        let (mut outboxes, inboxes) = channels(self.config.workers)?;
+
+        // `Publisher`s taken before now start reaching the loops
+        let _ = self.publisher.loops.set(outboxes.clone());
+
+        let mut loops: Vec<EventLoop> = inboxes.into_iter()
+            .map(|inbox| self.event_loop(inbox))
+            .collect();
+
+        let acceptor = loops.last_mut().expect("At least one event loop");
+        outboxes.pop(); // Its own, it serves its share of connections directly
+
+        acceptor.listener = Some(self.listener.try_clone()?);
+        acceptor.redirect_listener = self.redirect_listener.as_ref().map(TcpListener::try_clone).transpose()?;
+        acceptor.reserve = Some(File::open(RESERVE_PATH)?);
+        acceptor.handoff = outboxes;
+
+        Ok(loops)
+    }
+
+    fn event_loop(&self, inbox: Inbox) -> EventLoop<'_> {
+        EventLoop {
+            listener: None,
+            redirect_listener: None,
+            handoff: vec![],
+            next_loop: 0,
+            clients: Clients::new(),
+            config: self.config.clone(),
+            poller: inbox.poller,
+            logger: self.logger.clone(),
+            websocket: self.websocket.as_deref(),
+            publisher: self.publisher.clone(),
+            published: inbox.published,
+            accepted: inbox.accepted,
+            history: HashMap::new(),
+            connections: self.connections.clone(),
+            limiter: self.limiter.clone(),
+            reserve: None,
+            lingering: VecDeque::new(),
+            #[cfg(feature = "tls")]
+            tls: self.tls.clone(),
+            #[cfg(feature = "tls")]
+            tls_reloader: tls::Reloader::new(&self.config)
+        }
+    }
+}
+
+
+// A single event loop, each one runs on its own thread and has its own connections. One of them
+// accepts connections for all of them, which go through the other loops' inboxes. Topics span all
+// of them too, anything published goes through every loop's inbox.
+struct EventLoop<'a> {
+    listener: Option<TcpListener>, // Only set on the acceptor, like everything up to `next_loop`
+    redirect_listener: Option<TcpListener>,
+    handoff: Vec<Outbox>, // The other loops, accepted connections are handed to them in turn
+    next_loop: usize, // Index into `handoff` of the loop getting the next connection, past the end for this one
+    clients: Clients,
+    config: Arc<Config>,
+    poller: Arc<Poller>,
+    logger: Logger,
+    websocket: Option<&'a websocket::Handler>,
+    publisher: Publisher, // For publishes from WebSocket handlers, which have to reach the other loops too
+    published: Receiver<Published>, // Sent by `Publisher`s, waiting to go out
+    accepted: Receiver<Accepted>, // Handed over by the acceptor, waiting to be added
+    history: HashMap<String, History>, // Recent events on each event stream topic
+    connections: Arc<AtomicUsize>, // Shared by all event loops, for `Config::max_connections`
+    limiter: Arc<RateLimiter>, // Also shared, for the per-client limits
+    reserve: Option<File>, // Closed to make room for accepting a connection when out of file descriptors
+    lingering: VecDeque<(TcpStream, Instant)>, // Turned away connections & when to close them, oldest first
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>, // Set if connections are wrapped in TLS, used for new connections
+    #[cfg(feature = "tls")]
+    tls_reloader: Option<tls::Reloader>
+}
+
+impl EventLoop<'_> {
+
+    fn run<F: Fn(Request, Arc<Config>, Logger) -> Response>(mut self, cb: &F) {
+        if let Some(listener) = &self.listener {
+            if let Err(e) = self.poller.add_with_mode(listener, Event::readable(0), PollMode::Level) {
+                log!(self.logger, LogLevel::Error, "Error adding TcpListener to Poller: {}", e);
+            }
+        }
+
+        if let Some(listener) = &self.redirect_listener {
+            if let Err(e) = self.poller.add_with_mode(listener, Event::readable(REDIRECT_KEY), PollMode::Level) {
+                log!(self.logger, LogLevel::Error, "Error adding redirect TcpListener to Poller: {}", e);
+            }
         }
 
         let mut events = vec![];
-        let mut prev_time = Instant::now();
 
         loop {
             events.clear();
+            let timeout = self.next_timeout();
 
-            if let Err(e) = self.poller.wait(&mut events, self.clients.lowest_lifetime()) {
+            if let Err(e) = self.poller.wait(&mut events, timeout) {
                 log!(self.logger, LogLevel::Error, "Error waiting for events: {}", e);
             }
 
-            log!(self.logger, LogLevel::Debug, "Processing {} event(s)", events.len());
+            #[cfg(feature = "tls")]
+            self.reload_tls();
 
-            // Subtract the elapsed time from all clients
-            let now = Instant::now();
-            let delta = now.duration_since(prev_time);
-            self.clients.sub_duration(delta);
-            prev_time = now;
+            log!(self.logger, LogLevel::Debug, "Processing {} event(s)", events.len());
 
-            // No events, client timeout occurred
-            if events.len() == 0 {
-                let removed = self.clients.remove_inactive();
+            // Handed over by the acceptor, which also wakes the poller up
+            while let Ok(accepted) = self.accepted.try_recv() {
+                self.add_client(accepted);
+            }
 
-                for client in &removed {
-                    if let Err(e) = self.poller.delete(&client.stream) {
-                        log!(self.logger, LogLevel::Error, "Error removing poller stream: {}", e);
-                    }
+            // Sent by `Publisher`s, which wake the poller up after each one
+            while let Ok(published) = self.published.try_recv() {
+                match published {
+                    Published::Message(topic, message) => self.publish(&topic, &message),
+                    Published::Event(topic, event) => self.send_event(&topic, event)
                 }
-
-                log!(self.logger, LogLevel::Info, "Timed out {} client(s) (total: {})", removed.len(), self.clients.len());
-                continue;
             }
 
+            // Checked on every wakeup, busy connections can keep the poller from ever timing out
+            self.expire_clients();
+            self.close_lingering();
+
             // Handle all events
             for ev in &events {
-                // TcpListener event
+                // TcpListener events
                 if ev.key == 0 {
-                    match self.listener.accept() {
-                        // Add a client and register it with the poller
-                        Ok((stream, peer_addr)) => {
-                            let (key, client) = self.clients.add(stream, peer_addr);
-
-                            match self.poller.add_with_mode(&client.stream, Event::readable(key), PollMode::Level) {
-                                Err(e) => log!(self.logger, LogLevel::Error, "Error adding client to poller: {}", e),
-                                Ok(_) => log!(self.logger, LogLevel::Info, "New client: {} (total: {})", key, self.clients.len())
-                            }
-                        },
-
-                        Err(e) => log!(self.logger, LogLevel::Error, "Error accepting TcpStream: {}", e)
-                    }
+                    self.accept(false);
+                }
+
+                else if ev.key == REDIRECT_KEY {
+                    self.accept(true);
                 }
 
-                // Client event
-                else if let Some(client) = self.clients.get_mut(ev.key) {
-                    let mut buf = Box::new([0u8; 2048]);
-
-                    match client.read(buf.as_mut_slice()) {
-                        // No bytes to read, usually indicates closed remote side, so we just
-                        // remove the client
-                        Ok(n) if n == 0 => {
-                            match self.clients.remove(&ev.key) {
-                                Some(client) => {
-                                    match self.poller.delete(&client.stream) {
-                                        Err(e) => log!(self.logger, LogLevel::Error, "Error removing poller stream: {}", e),
-                                        Ok(_) => log!(self.logger, LogLevel::Info, "Client {} removed (total: {})", ev.key, self.clients.len())
-                                    }
-                                },
-
-                                None => log!(self.logger, LogLevel::Warning, "Failed to find client with key: {}", ev.key)
-                            }
-                        },
-
-                        // Some bytes read, parse the Request and do something with it
-                        Ok(n) if n > 0 => {
-                            let mut headers = [EMPTY_HEADER; 32];
-                            let mut req = Request::new(&mut headers);
-
-                            // TODO: Move this match block into a separate function to make error
-                            // handling (failed to send response) easier
-                            match req.parse(&buf[0..n]) {
-                                Ok(httparse::Status::Complete(_)) => {
-                                    let response = cb(req, self.config.clone(), self.logger.clone());
-                                    let status = response.status.clone();
-
-                                    match client.send(response) {
-                                        Ok(()) => log!(self.logger, LogLevel::Debug, "Sent response: {:?}", status),
-                                        Err(e) => log!(self.logger, LogLevel::Error, "Error sending response: {}", e)
-                                    }
-                                },
-                                Ok(httparse::Status::Partial) => {
-                                    log!(self.logger, LogLevel::Warning, "Partial request, replying with 400 Bad Request");
-
-                                    let res = Response::text(Status::BadRequest, "400 Bad Request");
-
-                                    if let Err(e) = client.send(res) {
-                                        log!(self.logger, LogLevel::Error, "Error sending response: {}", e);
-                                    }
-                                },
-                                Err(e) => {
-                                    log!(self.logger, LogLevel::Error, "Bad request: {}", e);
-
-                                    let res = Response::text(Status::BadRequest, "400 Bad Request");
-
-                                    if let Err(e) = client.send(res) {
-                                        log!(self.logger, LogLevel::Error, "Error sending response: {}", e);
-                                    }
-                                }
-                            }
-                        },
-
-                        Err(e) => log!(self.logger, LogLevel::Error, "Error reading from socket: {}", e),
-                        Ok(_) => unreachable!()
+                // Client event, the socket can also have become writable for queued output
+                else if self.clients.get(ev.key).is_some() {
+                    if ev.writable {
+                        self.flush_client(ev.key);
+                    }
+
+                    if ev.readable {
+                        self.read_client(ev.key, cb);
                     }
                 }
 
@@ -156,4 +317,1510 @@ impl Server {
             }
         }
     }
+
+    // Accept a connection, from the redirect listener if `redirect` is set, and hand it to the
+    // next event loop in turn
+    fn accept(&mut self, redirect: bool) {
+        let listener = match redirect {
+            true => self.redirect_listener.as_ref(),
+            false => self.listener.as_ref()
+        };
+
+        let (stream, peer_addr) = match listener.unwrap().accept() {
+            Ok(accepted) => accepted,
+
+            // The client gave up while waiting in the backlog
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+
+            // The connection stays in the backlog, which would wake the poller right back up
+            Err(e) if matches!(e.raw_os_error(), Some(EMFILE | ENFILE)) => {
+                self.shed_connection(redirect);
+                return;
+            },
+
+            Err(e) => {
+                log!(self.logger, LogLevel::Error, "Error accepting TcpStream: {}", e);
+                return;
+            }
+        };
+
+        if self.connections.fetch_add(1, Ordering::Relaxed) >= self.config.max_connections {
+            self.connections.fetch_sub(1, Ordering::Relaxed);
+            log!(self.logger, LogLevel::Warning, "Too many connections, turning {} away", peer_addr);
+
+            let stream = self.turn_away(stream, redirect, unavailable());
+            self.linger(stream);
+            return;
+        }
+
+        if let Err(limited) = self.limiter.connect(peer_addr.ip()) {
+            self.connections.fetch_sub(1, Ordering::Relaxed);
+            log!(self.logger, LogLevel::Warning, "Too many connections from {}, turning it away", peer_addr.ip());
+
+            let stream = self.turn_away(stream, redirect, limited.response());
+            self.linger(stream);
+            return;
+        }
+
+        // A client that's slow to read must not hold up everyone else, so writes that would block
+        // are queued instead
+        if let Err(e) = stream.set_nonblocking(true) {
+            log!(self.logger, LogLevel::Error, "Error switching connection to non-blocking: {}", e);
+            self.connections.fetch_sub(1, Ordering::Relaxed);
+            self.limiter.disconnect(peer_addr.ip());
+            return;
+        }
+
+        let accepted = Accepted { stream, peer_addr, redirect };
+        let next = self.next_loop;
+        self.next_loop = (next + 1) % (self.handoff.len() + 1);
+
+        let Some(outbox) = self.handoff.get(next) else {
+            self.add_client(accepted);
+            return;
+        };
+
+        // A loop that's gone can't take it, so it's served here instead
+        match outbox.accepted.send(accepted) {
+            Ok(_) => {
+                if let Err(e) = outbox.poller.notify() {
+                    log!(self.logger, LogLevel::Error, "Error waking up event loop: {}", e);
+                }
+            },
+
+            Err(mpsc::SendError(accepted)) => self.add_client(accepted)
+        }
+    }
+
+    // Add a client for an accepted connection and register it with the poller
+    fn add_client(&mut self, accepted: Accepted) {
+        let Accepted { stream, peer_addr, redirect } = accepted;
+
+        let (key, client) = match self.clients.add(stream, peer_addr, self.config.keep_alive_timeout) {
+            Ok(added) => added,
+            Err(stream) => {
+                self.connections.fetch_sub(1, Ordering::Relaxed);
+                self.limiter.disconnect(peer_addr.ip());
+                log!(self.logger, LogLevel::Warning, "No room for another client, turning {} away", peer_addr);
+
+                let stream = self.turn_away(stream, redirect, unavailable());
+                self.linger(stream);
+                return;
+            }
+        };
+
+        match self.poller.add_with_mode(&client.stream, Event::readable(key), PollMode::Level) {
+            Err(e) => log!(self.logger, LogLevel::Error, "Error adding client to poller: {}", e),
+            Ok(_) => log!(self.logger, LogLevel::Info, "New client: {} (total: {})", key, self.clients.len())
+        }
+
+        if redirect {
+            if let Some(client) = self.clients.get_mut(key) {
+                client.redirect = true;
+            }
+        }
+        else {
+            #[cfg(feature = "tls")]
+            self.start_tls(key);
+        }
+    }
+
+    // Out of file descriptors, close the reserve for long enough to accept a connection & turn it
+    // away, instead of leaving it to wait for a slot that may not open up any time soon
+    fn shed_connection(&mut self, redirect: bool) {
+        log!(self.logger, LogLevel::Warning, "Out of file descriptors, turning a connection away");
+
+        self.reserve = None;
+
+        let listener = match redirect {
+            true => self.redirect_listener.as_ref(),
+            false => self.listener.as_ref()
+        };
+
+        // It can't linger, that would keep the reserve from being reopened
+        if let Ok((stream, _)) = listener.unwrap().accept() {
+            drain(&mut self.turn_away(stream, redirect, unavailable()));
+        }
+
+        match File::open(RESERVE_PATH) {
+            Ok(file) => self.reserve = Some(file),
+            Err(e) => log!(self.logger, LogLevel::Error, "Error reopening reserve file descriptor: {}", e)
+        }
+    }
+
+    // Answer a connection that can't be served right now with `response` (a 503 or 429) and shut
+    // down our side of it, returning the stream for closing. Connections expecting TLS are left
+    // as-is, there's no sending anything without a handshake.
+    fn turn_away(&self, mut stream: TcpStream, redirect: bool, mut response: Response) -> TcpStream {
+        if !redirect && self.uses_tls() {
+            return stream;
+        }
+
+        response.set_keep_alive(None);
+
+        // Best effort, it's small enough to go out in a single write on a new connection
+        let _ = stream.set_nonblocking(true);
+
+        for block in response.encode(1, true).flatten() {
+            if stream.write_all(&block).is_err() {
+                break;
+            }
+        }
+
+        let _ = stream.shutdown(Shutdown::Write);
+        stream
+    }
+
+    // Keep a turned away connection open for a little while, so the client gets to read its
+    // response before the connection is closed
+    fn linger(&mut self, mut stream: TcpStream) {
+        drain(&mut stream);
+
+        if self.lingering.len() >= MAX_LINGERING {
+            if let Some((mut oldest, _)) = self.lingering.pop_front() {
+                drain(&mut oldest);
+            }
+        }
+
+        self.lingering.push_back((stream, Instant::now() + LINGER));
+    }
+
+    // Close the turned away connections that have lingered for long enough
+    fn close_lingering(&mut self) {
+        let now = Instant::now();
+
+        while self.lingering.front().is_some_and(|(_, deadline)| *deadline <= now) {
+            if let Some((mut stream, _)) = self.lingering.pop_front() {
+                drain(&mut stream);
+            }
+        }
+    }
+
+    fn uses_tls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return true;
+        }
+
+        false
+    }
+
+    // How long the poller can wait before a client times out, a turned away connection is closed
+    // (or certificates need checking)
+    fn next_timeout(&mut self) -> Option<Duration> {
+        let deadline = match (self.clients.next_deadline(), self.lingering.front()) {
+            (Some(client), Some((_, lingering))) => Some(client.min(*lingering)),
+            (client, lingering) => client.or(lingering.map(|(_, deadline)| *deadline))
+        };
+
+        let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+
+        #[cfg(feature = "tls")]
+        if let Some(reloader) = &self.tls_reloader {
+            return Some(timeout.map_or(reloader.timeout(), |timeout| timeout.min(reloader.timeout())));
+        }
+
+        timeout
+    }
+
+    // Swap in the new TLS configuration if the certificate or key files changed. Connections that
+    // are already open keep using the old one, which is also kept if the new files can't be loaded.
+    #[cfg(feature = "tls")]
+    fn reload_tls(&mut self) {
+        if !self.tls_reloader.as_mut().is_some_and(tls::Reloader::changed) {
+            return;
+        }
+
+        match tls::server_config(&self.config) {
+            Ok(config) => {
+                self.tls = config;
+                log!(self.logger, LogLevel::Info, "Reloaded TLS certificates");
+            },
+
+            Err(e) => log!(self.logger, LogLevel::Error, "Error reloading TLS certificates, keeping the old ones: {}", e)
+        }
+    }
+
+    // Read whatever a client sent and handle it
+    fn read_client<F: Fn(Request, Arc<Config>, Logger) -> Response>(&mut self, key: usize, cb: &F) {
+        let Some(client) = self.clients.get_mut(key) else {
+            return;
+        };
+
+        let handled = match client.receive() {
+            // No bytes to read, usually indicates closed remote side, so we just remove the client
+            Ok(0) => Handled::Close,
+
+            // Some bytes read, try to parse Requests (or WebSocket frames) out of everything
+            // received so far
+            Ok(_) => handle_client(client, cb, self.websocket, &self.limiter, &self.config, &self.logger),
+
+            // Spurious wakeup, or a TLS record that didn't have any data in it
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Handled::Waiting,
+
+            // Includes TLS errors, there's no recovering from those either
+            Err(e) => {
+                log!(self.logger, LogLevel::Error, "Error reading from socket: {}", e);
+                Handled::Close
+            }
+        };
+
+        // Taken before the client is (possibly) removed below, so publishes aren't lost
+        let actions = client.websocket.as_mut().map(WebSocket::take_actions).unwrap_or_default();
+
+        match handled {
+            Handled::Waiting | Handled::KeepAlive => self.refresh_client(key),
+            Handled::Close => self.close_client(key),
+            Handled::Stream => self.start_stream(key)
+        }
+
+        self.run_actions(key, actions);
+        self.check_output(key);
+    }
+
+    // Carry out the actions a WebSocket handler asked for
+    fn run_actions(&mut self, key: usize, actions: Vec<Action>) {
+        for action in actions {
+            match action {
+                Action::Subscribe(topic) => self.clients.subscribe(key, &topic),
+                Action::Unsubscribe(topic) => self.clients.unsubscribe(key, &topic),
+                Action::Publish(topic, message) => {
+                    self.publisher.publish(&topic, message);
+                }
+            }
+        }
+    }
+
+    // Send a message to every subscriber of a topic. Each subscriber has its own queue, so one
+    // that's slow to read doesn't hold up the others.
+    fn publish(&mut self, topic: &str, message: &Message) {
+        let subscribers = self.clients.subscribers(topic);
+        log!(self.logger, LogLevel::Debug, "Publishing to {} subscriber(s) of {:?}", subscribers.len(), topic);
+
+        for key in subscribers {
+            if let Some(socket) = self.clients.get_mut(key).and_then(|client| client.websocket.as_mut()) {
+                socket.send(message.clone());
+            }
+
+            self.flush_client(key);
+        }
+    }
+
+    // Start a TLS session on a new connection, the handshake happens as data comes in
+    #[cfg(feature = "tls")]
+    fn start_tls(&mut self, key: usize) {
+        let (Some(config), Some(client)) = (&self.tls, self.clients.get_mut(key)) else {
+            return;
+        };
+
+        match rustls::ServerConnection::new(config.clone()) {
+            Ok(connection) => client.tls = Some(connection),
+            Err(e) => {
+                log!(self.logger, LogLevel::Error, "Error starting TLS session: {}", e);
+                self.remove_client(key);
+            }
+        }
+    }
+
+    // Subscribe a new event stream to its topic, catching it up on the events it missed if it's
+    // reconnecting
+    fn start_stream(&mut self, key: usize) {
+        let Some(stream) = self.clients.get_mut(key).and_then(|client| client.event_stream.as_mut()) else {
+            return;
+        };
+
+        let topic = stream.topic().to_string();
+
+        if let (Some(last_id), Some(history)) = (stream.take_last_event_id(), self.history.get(&topic)) {
+            for event in history.since(&last_id) {
+                stream.send(event);
+            }
+        }
+
+        log!(self.logger, LogLevel::Info, "Client {} is streaming events from {:?}", key, topic);
+
+        self.clients.subscribe(key, &topic);
+        self.refresh_client(key);
+        self.flush_client(key);
+    }
+
+    // Send an event to every event stream on a topic, keeping it around for clients that reconnect
+    fn send_event(&mut self, topic: &str, event: sse::Event) {
+        let subscribers = self.clients.subscribers(topic);
+        log!(self.logger, LogLevel::Debug, "Sending event to {} stream(s) of {:?}", subscribers.len(), topic);
+
+        for key in subscribers {
+            if let Some(stream) = self.clients.get_mut(key).and_then(|client| client.event_stream.as_mut()) {
+                stream.send(&event);
+                self.refresh_client(key);
+            }
+
+            self.flush_client(key);
+        }
+
+        if self.config.sse_history > 0 {
+            self.history.entry(topic.to_string())
+                .or_insert_with(|| History::new(self.config.sse_history))
+                .push(event);
+        }
+    }
+
+    // Deal with the clients whose deadline has passed. Event streams don't time out, instead
+    // they're sent a comment whenever they've been idle for a while so nothing in between closes
+    // them. Clients that were too slow sending a request are told so before being closed.
+    fn expire_clients(&mut self) {
+        let mut count = 0;
+
+        for key in self.clients.expired(Instant::now()) {
+            let Some(client) = self.clients.get_mut(key) else {
+                continue;
+            };
+
+            // Event streams that stopped reading time out like any other connection
+            if let Some(stream) = client.event_stream.as_mut().filter(|_| !matches!(client.phase, Phase::Writing(_))) {
+                stream.comment("keepalive");
+
+                self.refresh_client(key);
+                self.flush_client(key);
+            }
+
+            // A 408 can't be sent before the TLS handshake is done
+            else if matches!(client.phase, Phase::Head(_) | Phase::Body(_)) && client.websocket.is_none() && !client.is_handshaking() {
+                log!(self.logger, LogLevel::Warning, "Client {} took too long to send a request", key);
+
+                let mut response = Response::text(Status::RequestTimeout, Status::RequestTimeout.as_str());
+                response.set_keep_alive(None);
+
+                client.buffer.clear();
+                client.queue(response, 1, true);
+                self.close_client(key);
+                self.flush_client(key);
+            }
+
+            else if let Some(client) = self.clients.remove(&key) {
+                self.drop_client(client);
+                count += 1;
+            }
+        }
+
+        if count > 0 {
+            log!(self.logger, LogLevel::Info, "Timed out {} client(s) (total: {})", count, self.clients.len());
+        }
+    }
+
+    // Write as much of a client's queued output as its socket will take. Making progress counts
+    // as activity, a large response to a slow reader can take a while.
+    fn flush_client(&mut self, key: usize) {
+        let Some(client) = self.clients.get_mut(key) else {
+            return;
+        };
+
+        let closed = client.closing || client.websocket.as_ref().is_some_and(WebSocket::is_closed);
+
+        match client.flush_output() {
+            Err(e) => {
+                log!(self.logger, LogLevel::Error, "Error sending to client {}: {}", key, e);
+                self.remove_client(key);
+            },
+
+            Ok(_) if closed && !client.has_pending_output() => self.remove_client(key),
+
+            Ok(written) => {
+                if written > 0 {
+                    self.refresh_client(key);
+                }
+
+                self.check_output(key);
+            }
+        }
+    }
+
+    // Drop a client whose unsent output has piled up past the limit, otherwise update what the
+    // poller watches its socket for. Writability only matters while there's output left, and
+    // plain HTTP connections aren't read from until their responses are out, so a client that
+    // never reads can't make the server queue responses without end.
+    fn check_output(&mut self, key: usize) {
+        let Some(client) = self.clients.get_mut(key) else {
+            return;
+        };
+
+        // Responses are only encoded as they're written, what piles up is WebSocket frames & events
+        let upgraded = client.websocket.is_some() || client.event_stream.is_some();
+
+        if upgraded && client.pending.len() > self.config.max_send_queue {
+            log!(self.logger, LogLevel::Warning, "Client {} is too slow, dropping it with {} byte(s) unsent", key, client.pending.len());
+            self.remove_client(key);
+            return;
+        }
+
+        let writable = client.has_pending_output();
+        let readable = !client.closing && (upgraded || !writable);
+
+        let interest = Event { key, readable, writable };
+
+        if interest != client.interest {
+            match self.poller.modify_with_mode(&client.stream, interest, PollMode::Level) {
+                Ok(_) => client.interest = interest,
+                Err(e) => log!(self.logger, LogLevel::Error, "Error updating poller interest: {}", e)
+            }
+        }
+    }
+
+    // Give a client a new deadline for what it's waiting on. WebSocket connections are allowed to
+    // idle for longer and event streams are sent a keepalive when theirs runs out, but output that
+    // isn't being read times out the same for every connection. A request that's still arriving
+    // has a deadline that trickling in more of it doesn't push back (much).
+    fn refresh_client(&mut self, key: usize) {
+        let Some(client) = self.clients.get_mut(key) else {
+            return;
+        };
+
+        let config = &self.config;
+        let now = Instant::now();
+
+        let deadline = match client.update_phase(now) {
+            Phase::Writing(since) => since + config.write_timeout,
+
+            _ if client.websocket.is_some() => now + config.websocket_timeout,
+            _ if client.event_stream.is_some() => now + config.sse_keepalive,
+
+            Phase::Idle => now + config.keep_alive_timeout,
+            Phase::Head(start) => start + config.header_timeout,
+
+            // A large body gets more time, as long as it keeps arriving at the minimum rate
+            Phase::Body(start) => match config.min_body_rate {
+                0 => now + config.body_timeout,
+                rate => {
+                    let received = client.parser.body_received().unwrap_or_default();
+                    start + config.body_timeout + Duration::from_secs_f64(received as f64 / rate as f64)
+                }
+            }
+        };
+
+        self.clients.reschedule(key, deadline);
+    }
+
+    // Close a client once the rest of its output has been written
+    fn close_client(&mut self, key: usize) {
+        match self.clients.get_mut(key) {
+            Some(client) if client.has_pending_output() => {
+                client.closing = true;
+                self.refresh_client(key);
+            },
+
+            _ => self.remove_client(key)
+        }
+    }
+
+    fn remove_client(&mut self, key: usize) {
+        match self.clients.remove(&key) {
+            Some(client) => {
+                self.drop_client(client);
+                log!(self.logger, LogLevel::Info, "Client {} removed (total: {})", key, self.clients.len());
+            },
+
+            None => log!(self.logger, LogLevel::Warning, "Failed to find client with key: {}", key)
+        }
+    }
+
+    // Unregister a client that's been removed, letting the WebSocket handler know if its
+    // connection went away without a close handshake
+    fn drop_client(&mut self, mut client: Client) {
+        if let Err(e) = self.poller.delete(&client.stream) {
+            log!(self.logger, LogLevel::Error, "Error removing poller stream: {}", e);
+        }
+
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+        self.limiter.disconnect(client.address.ip());
+
+        let mut actions = vec![];
+
+        if let (Some(socket), Some(handler)) = (&mut client.websocket, self.websocket) {
+            if !socket.is_closed() {
+                // Best effort, the other side may already be gone
+                socket.close(websocket::CLOSE_GOING_AWAY, "");
+                let _ = client.flush_output();
+
+                let socket = client.websocket.as_mut().unwrap();
+                let event = websocket::Event::Close(websocket::CLOSE_ABNORMAL, String::new());
+                handler(socket, event, self.config.clone(), self.logger.clone());
+                actions = socket.take_actions();
+            }
+        }
+
+        client.shutdown();
+
+        // The handler can still publish a goodbye, but the client won't receive anything itself
+        self.run_actions(client.id, actions);
+    }
+}
+
+
+// Something sent to a topic by a `Publisher`
+#[derive(Clone)]
+enum Published {
+    Message(String, Message), // For WebSocket subscribers
+    Event(String, sse::Event) // For event streams
+}
+
+
+// A connection the acceptor handed to another event loop, already counted against the limits
+struct Accepted {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    redirect: bool // From the redirect listener
+}
+
+
+// An event loop's end of the channels `Publisher`s & the acceptor send to
+struct Inbox {
+    poller: Arc<Poller>,
+    published: Receiver<Published>,
+    accepted: Receiver<Accepted>
+}
+
+// The other end, with the poller to wake the loop up after sending
+#[derive(Clone)]
+struct Outbox {
+    poller: Arc<Poller>,
+    published: Sender<Published>,
+    accepted: Sender<Accepted>
+}
+
+
+// Publishes to WebSocket & event stream topics from anywhere, including other threads. Everything
+// is sent to each event loop, which gets woken up for it and sends it out to its own subscribers.
+#[derive(Clone)]
+pub struct Publisher {
+    loops: Arc<OnceLock<Vec<Outbox>>> // Set by `Server::listen`, shared with the `Publisher`s taken before
+}
+
+impl Publisher {
+    // Send a message to every WebSocket subscribed to `topic`. Returns `false` if the server isn't
+    // running (yet or anymore).
+    pub fn publish(&self, topic: &str, message: Message) -> bool {
+        self.send(Published::Message(topic.to_string(), message))
+    }
+
+    // Send an event to every event stream on `topic`
+    pub fn send_event(&self, topic: &str, event: sse::Event) -> bool {
+        self.send(Published::Event(topic.to_string(), event))
+    }
+
+    fn send(&self, published: Published) -> bool {
+        let Some(loops) = self.loops.get() else {
+            return false;
+        };
+
+        loops.iter().all(|outbox| outbox.published.send(published.clone()).is_ok() && outbox.poller.notify().is_ok())
+    }
+}
+
+
+// The channels to & from `workers` event loops
+fn channels(workers: usize) -> io::Result<(Vec<Outbox>, Vec<Inbox>)> {
+    let mut outboxes = vec![];
+    let mut inboxes = vec![];
+
+    for _ in 0..workers.max(1) {
+        let (published_sender, published) = mpsc::channel();
+        let (accepted_sender, accepted) = mpsc::channel();
+        let poller = Arc::new(Poller::new()?);
+
+        outboxes.push(Outbox { poller: poller.clone(), published: published_sender, accepted: accepted_sender });
+        inboxes.push(Inbox { poller, published, accepted });
+    }
+
+    Ok((outboxes, inboxes))
+}
+
+
+// The 503 for connections turned away while the server is at its limit
+fn unavailable() -> Response {
+    Builder::with_status(Status::ServiceUnavailable)
+        .add_header("Retry-After", RETRY_AFTER.as_secs().to_string())
+        .set_body(Status::ServiceUnavailable.as_str().as_bytes().to_vec())
+        .build()
+}
+
+
+// Read & discard whatever a turned away connection sent, without blocking. Closing it with that
+// still unread would reset the connection.
+fn drain(stream: &mut TcpStream) {
+    let mut buffer = [0u8; 4096];
+
+    // Enough for a request or two, a client sending more than that can take the reset
+    for _ in 0..16 {
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => ()
+        }
+    }
+}
+
+
+// What to do with a connection after handling its receive buffer
+enum Handled {
+    Waiting, // No complete request yet
+    KeepAlive, // Responded, keep the connection open for more requests
+    Close, // Responded (or failed to), close the connection
+    Stream // Responded with the head of an event stream, the connection stays open for events
+}
+
+
+// Handle newly received data, depending on whether the connection has been upgraded or not
+fn handle_client<F>(client: &mut Client, cb: &F, websocket: Option<&websocket::Handler>, limiter: &RateLimiter, config: &Arc<Config>, logger: &Logger) -> Handled
+    where F: Fn(Request, Arc<Config>, Logger) -> Response
+{
+    // Event streams only go one way, anything the client sends is ignored
+    if client.event_stream.is_some() {
+        client.buffer.clear();
+        return Handled::Waiting;
+    }
+
+    match (&client.websocket, websocket) {
+        (Some(_), Some(handler)) => handle_websocket(client, handler, config, logger),
+        _ => handle_buffer(client, cb, websocket, limiter, config, logger)
+    }
+}
+
+
+// Parse every complete Request out of a client's receive buffer and respond to them in order,
+// leaving partial requests in the buffer until more data arrives
+fn handle_buffer<F>(client: &mut Client, cb: &F, websocket: Option<&websocket::Handler>, limiter: &RateLimiter, config: &Arc<Config>, logger: &Logger) -> Handled
+    where F: Fn(Request, Arc<Config>, Logger) -> Response
+{
+    let mut handled = Handled::Waiting;
+
+    // Pipelined requests can arrive together, queue a response for each before writing them out.
+    // Anything following an upgrade request is made of WebSocket frames instead, and nothing
+    // else is answered once an event stream has started.
+    while !matches!(handled, Handled::Close | Handled::Stream) && client.websocket.is_none() {
+        match queue_response(client, cb, websocket, limiter, config, logger) {
+            Handled::Waiting => break,
+            result => handled = result
+        }
+    }
+
+    if let Err(e) = client.flush_output() {
+        log!(logger, LogLevel::Error, "Error sending response: {}", e);
+        return Handled::Close;
+    }
+
+    match (&client.websocket, websocket) {
+        (Some(_), Some(handler)) if !matches!(handled, Handled::Close) => handle_websocket(client, handler, config, logger),
+        _ => handled
+    }
+}
+
+
+// Parse a single Request from the front of a client's receive buffer and queue its response
+fn queue_response<F>(client: &mut Client, cb: &F, websocket: Option<&websocket::Handler>, limiter: &RateLimiter, config: &Arc<Config>, logger: &Logger) -> Handled
+    where F: Fn(Request, Arc<Config>, Logger) -> Response
+{
+    let mut version = 1;
+    let mut with_body = true;
+    let mut keep_alive = false;
+    let mut last_event_id = None;
+
+    let mut response = match client.parser.parse(&mut client.buffer, config) {
+        Ok(Some((head, body))) => {
+            let request = Request::new(head, body, client.address, client.id);
+            client.requests += 1;
+            client.phase = Phase::Idle; // Whatever follows in the buffer is the next request
+
+            let limited = limiter.check(client.address.ip(), Instant::now()).err();
+
+            if let Some(handler) = websocket.filter(|_| limited.is_none() && !client.redirect && websocket::is_upgrade(&request)) {
+                return upgrade(client, request, handler, config, logger);
+            }
+
+            version = request.version;
+            with_body = request.method != Method::Head;
+            keep_alive = request.keep_alive() && client.requests < config.max_requests;
+            last_event_id = request.headers.get_str("Last-Event-ID").map(String::from);
+
+            match limited {
+                Some(limited) => {
+                    log!(logger, LogLevel::Warning, "Rate limited request from {}: {} {}", request.peer, request.method, request.target);
+                    limited.response()
+                },
+
+                None if client.redirect && !redirect::is_exempt(&request, config) => redirect::response(&request, config),
+                None => cb(request, config.clone(), logger.clone())
+            }
+        },
+
+        Ok(None) => return Handled::Waiting,
+
+        Err(e) => {
+            log!(logger, LogLevel::Warning, "Bad request: {}", e);
+
+            let status = e.status();
+            Response::text(status.clone(), status.as_str())
+        }
+    };
+
+    // The stream only ends along with the connection
+    if let Body::Events(topic) = &response.body {
+        if with_body {
+            client.event_stream = Some(EventStream::new(topic.clone(), version > 0, last_event_id));
+            response.set_keep_alive(None);
+            client.queue(response, version, with_body);
+
+            return Handled::Stream;
+        }
+    }
+
+    // HTTP/1.0 bodies without a length can only be delimited by closing the connection
+    keep_alive &= !response.closes_connection() && (version > 0 || !with_body || response.body.len().is_some());
+    response.set_keep_alive(keep_alive.then(|| (config.keep_alive_timeout, config.max_requests - client.requests)));
+
+    log!(logger, LogLevel::Debug, "Queued response: {:?}", response.status);
+    client.queue(response, version, with_body);
+
+    match keep_alive {
+        true => Handled::KeepAlive,
+        false => Handled::Close
+    }
+}
+
+
+// Answer a WebSocket handshake and switch the connection over to WebSocket frames
+fn upgrade(client: &mut Client, request: Request, handler: &websocket::Handler, config: &Arc<Config>, logger: &Logger) -> Handled {
+    let Some((response, deflate)) = websocket::handshake(&request, config) else {
+        log!(logger, LogLevel::Warning, "Invalid WebSocket handshake from {}", request.peer);
+
+        let mut response = Response::text(Status::BadRequest, "400 Bad Request");
+        response.set_keep_alive(None);
+        client.queue(response, request.version, true);
+        return Handled::Close;
+    };
+
+    log!(logger, LogLevel::Info, "Upgrading client {} to a WebSocket connection", client.id);
+    client.queue(response, request.version, true);
+
+    let mut socket = WebSocket::new(client.id, client.address, config.max_message_size, deflate);
+    handler(&mut socket, websocket::Event::Open(request), config.clone(), logger.clone());
+    client.websocket = Some(socket);
+
+    Handled::KeepAlive
+}
+
+
+// Pass every complete WebSocket message in a client's receive buffer to the handler. Any data
+// counts as activity, so the connection is kept alive unless it's been closed.
+fn handle_websocket(client: &mut Client, handler: &websocket::Handler, config: &Arc<Config>, logger: &Logger) -> Handled {
+    let socket = client.websocket.as_mut().unwrap();
+
+    while let Some(event) = socket.next_event(&mut client.buffer) {
+        handler(socket, event, config.clone(), logger.clone());
+    }
+
+    let closed = socket.is_closed();
+
+    if let Err(e) = client.flush_output() {
+        log!(logger, LogLevel::Error, "Error sending WebSocket frames: {}", e);
+        return Handled::Close;
+    }
+
+    match closed {
+        true => Handled::Close,
+        false => Handled::KeepAlive
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{Ipv4Addr, SocketAddr, TcpStream},
+        sync::Arc,
+        thread,
+        time::{Duration, Instant}
+    };
+
+    use super::Server;
+    use crate::{
+        config::Config,
+        logging::{LogLevel, Logger},
+        request::Request,
+        response::Response,
+        sse,
+        testing::{self, echo_path, read_frame, read_head, read_response, read_text, send_text, start_server, websocket, LARGE_BODY},
+        websocket::{self, Message, WebSocket}
+    };
+
+
+    // Subscribes each WebSocket to its path & "/all", "leave" unsubscribes it from its path again
+    fn subscribe_to_path(socket: &mut WebSocket, event: websocket::Event, _: Arc<Config>, _: Logger) {
+        match event {
+            websocket::Event::Open(request) => {
+                // Twice, which still only gets it one copy of everything
+                socket.subscribe(&request.path);
+                socket.subscribe(&request.path);
+                socket.subscribe("/all");
+            },
+
+            websocket::Event::Message(Message::Text(text)) if text == "leave" => {
+                let topics: Vec<String> = socket.topics().filter(|topic| *topic != "/all").map(String::from).collect();
+
+                for topic in topics {
+                    socket.unsubscribe(&topic);
+                }
+
+                socket.send(Message::Text(String::from("left")));
+            },
+
+            _ => ()
+        }
+    }
+
+    fn text(text: &str) -> Message {
+        Message::Text(text.to_string())
+    }
+
+    // Every request starts an event stream on its path
+    fn stream_path(request: Request, _: Arc<Config>, _: Logger) -> Response {
+        Response::event_stream(&request.path)
+    }
+
+    // Open an event stream, checking its head
+    fn event_stream(address: SocketAddr, path: &str, last_event_id: Option<&str>) -> TcpStream {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        write!(stream, "GET {} HTTP/1.1\r\nHost: x\r\n", path).unwrap();
+
+        if let Some(id) = last_event_id {
+            write!(stream, "Last-Event-ID: {}\r\n", id).unwrap();
+        }
+
+        write!(stream, "\r\n").unwrap();
+
+        let head = read_head(&mut stream);
+        assert!(head.starts_with("HTTP/1.1 200 Ok\r\n"), "{}", head);
+        assert!(head.contains("\r\nContent-Type: text/event-stream\r\n"), "{}", head);
+        assert!(head.contains("\r\nTransfer-Encoding: chunked\r\n"), "{}", head);
+
+        stream
+    }
+
+    // Read a single chunk of an event stream, which holds one record
+    fn read_record(stream: &mut TcpStream) -> String {
+        let size = read_line(stream);
+        let mut data = vec![0; usize::from_str_radix(&size, 16).unwrap() + 2];
+        stream.read_exact(&mut data).unwrap();
+
+        assert!(data.ends_with(b"\r\n"));
+        data.truncate(data.len() - 2);
+        String::from_utf8(data).unwrap()
+    }
+
+    fn read_line(stream: &mut TcpStream) -> String {
+        let mut line = vec![];
+        let mut byte = [0u8; 1];
+
+        while !line.ends_with(b"\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            line.push(byte[0]);
+        }
+
+        line.truncate(line.len() - 2);
+        String::from_utf8(line).unwrap()
+    }
+
+    fn event(id: u32) -> sse::Event {
+        sse::Event::new(format!("line {}\nline two", id)).with_id(id.to_string())
+    }
+
+
+    #[test]
+    fn serves_many_concurrent_connections() {
+        // Long enough that no connection times out while the others are being served
+        let address = start_server(Config {
+            keep_alive_timeout: Duration::from_secs(60),
+            ..Config::default()
+        });
+
+        for round in 0..2 {
+            let mut streams: Vec<TcpStream> = (0..100)
+                .map(|_| TcpStream::connect(address).unwrap())
+                .collect();
+
+            // Every connection is open before any of them sends a request
+            for (i, stream) in streams.iter_mut().enumerate() {
+                write!(stream, "GET /{}/{} HTTP/1.1\r\nHost: x\r\n\r\n", round, i).unwrap();
+            }
+
+            for (i, stream) in streams.iter_mut().enumerate() {
+                assert_eq!(read_response(stream).1, format!("/{}/{}", round, i));
+            }
+
+            // Drop every other connection so that their slots get reused, then send a second
+            // request on the rest
+            let mut kept: Vec<(usize, TcpStream)> = streams.into_iter()
+                .enumerate()
+                .filter(|(i, _)| i % 2 == 1)
+                .collect();
+
+            for (i, stream) in &mut kept {
+                write!(stream, "GET /again/{} HTTP/1.1\r\nHost: x\r\n\r\n", i).unwrap();
+                assert_eq!(read_response(stream).1, format!("/again/{}", i));
+            }
+        }
+    }
+
+    #[test]
+    fn slow_readers_dont_block_others() {
+        let address = start_server(Config::default());
+
+        // Never reads its response, which can't all be written at once
+        let mut slow = TcpStream::connect(address).unwrap();
+        write!(slow, "GET /large HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        let mut fast = TcpStream::connect(address).unwrap();
+        fast.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(fast, "GET /fast HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut fast).1, "/fast");
+
+        // The slow client still gets all of it once it starts reading
+        assert_eq!(read_response(&mut slow).1.len(), LARGE_BODY);
+    }
+
+    #[test]
+    fn trickled_requests_time_out() {
+        let address = start_server(Config {
+            header_timeout: Duration::from_secs(1),
+            ..Config::default()
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // Each byte would refresh an idle timeout, the header timeout counts from the first one
+        let start = Instant::now();
+
+        for byte in b"GET / HTT" {
+            stream.write_all(&[*byte]).unwrap();
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{:?}", response);
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn bodies_below_the_minimum_rate_time_out() {
+        let address = start_server(Config {
+            body_timeout: Duration::from_secs(1),
+            min_body_rate: 1000,
+            ..Config::default()
+        });
+
+        // Arriving at the minimum rate, it gets more time than `body_timeout` alone would give it
+        let mut steady = TcpStream::connect(address).unwrap();
+        write!(steady, "POST /steady HTTP/1.1\r\nHost: x\r\nContent-Length: 4000\r\n\r\n").unwrap();
+
+        for _ in 0..10 {
+            steady.write_all(&[b'x'; 400]).unwrap();
+            thread::sleep(Duration::from_millis(200));
+        }
+
+        assert_eq!(read_response(&mut steady).1, "/steady");
+
+        // At half of it, it runs out of time after about two seconds
+        let mut slow = TcpStream::connect(address).unwrap();
+        write!(slow, "POST /slow HTTP/1.1\r\nHost: x\r\nContent-Length: 100000\r\n\r\n").unwrap();
+
+        let start = Instant::now();
+        let mut response = [0u8; 512];
+
+        let len = loop {
+            assert!(start.elapsed() < Duration::from_secs(5), "Never timed out");
+
+            slow.write_all(&[b'x'; 100]).unwrap();
+            thread::sleep(Duration::from_millis(200));
+
+            // Stops sending once the response is in, the server stopped reading
+            slow.set_nonblocking(true).unwrap();
+            let read = slow.read(&mut response);
+            slow.set_nonblocking(false).unwrap();
+
+            if let Ok(len) = read {
+                break len;
+            }
+        };
+
+        let response = String::from_utf8_lossy(&response[..len]);
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{:?}", response);
+        assert!(start.elapsed() > Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn closes_connections_that_stop_reading() {
+        let address = start_server(Config {
+            write_timeout: Duration::from_secs(1),
+            ..Config::default()
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET /large HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(2500));
+
+        // What was already in the socket buffers still arrives, then the connection ends
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut received = vec![];
+        stream.read_to_end(&mut received).unwrap();
+        assert!(received.len() < LARGE_BODY, "{} bytes", received.len());
+    }
+
+    #[test]
+    fn closes_upgraded_connections_that_stop_reading() {
+        const SIZE: usize = 256 * 1024;
+
+        // Nothing is dropped for being too slow, other than by the write timeout. A single loop
+        // handles what's published in order, so once "/done" arrives everything before it is queued.
+        let config = || Config {
+            write_timeout: Duration::from_secs(1),
+            max_send_queue: 2 * LARGE_BODY,
+            workers: 1,
+            ..Config::default()
+        };
+
+        let server = testing::server(config());
+        let publisher = server.publisher();
+        let address = testing::start(server, stream_path);
+        let mut events = event_stream(address, "/feed", None);
+        let mut events_done = event_stream(address, "/done", None);
+
+        let server = testing::server(config());
+        let sockets = server.publisher();
+        let address = testing::start(server.with_websocket(subscribe_to_path), echo_path);
+        let mut socket = websocket(address, "/feed");
+        let mut socket_done = websocket(address, "/done");
+
+        // More than fits in the socket buffers
+        for _ in 0..LARGE_BODY / SIZE {
+            assert!(publisher.send_event("/feed", sse::Event::new("x".repeat(SIZE))));
+            assert!(sockets.publish("/feed", Message::Binary(vec![7; SIZE])));
+        }
+
+        assert!(publisher.send_event("/done", sse::Event::new("done")));
+        assert!(sockets.publish("/done", text("done")));
+
+        // Encoding all of it can take a while in debug builds
+        for stream in [&events_done, &socket_done] {
+            stream.set_read_timeout(Some(Duration::from_secs(60))).unwrap();
+        }
+
+        assert_eq!(read_record(&mut events_done), "data: done\n\n");
+        assert_eq!(read_text(&mut socket_done), "done");
+
+        // Reading would count as progress, so this waits for the write timeout first
+        thread::sleep(Duration::from_secs(2));
+
+        // What was already in the socket buffers still arrives, then the connections end
+        for stream in [&mut events, &mut socket] {
+            let mut received = vec![];
+            stream.read_to_end(&mut received).unwrap();
+            assert!(received.len() < LARGE_BODY, "{} bytes", received.len());
+        }
+    }
+
+    #[test]
+    fn keeps_connections_in_use_past_the_keep_alive_timeout() {
+        let address = start_server(Config {
+            keep_alive_timeout: Duration::from_secs(1),
+            ..Config::default()
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let start = Instant::now();
+
+        for i in 0.. {
+            write!(stream, "GET /{} HTTP/1.1\r\nHost: x\r\n\r\n", i).unwrap();
+            assert_eq!(read_response(&mut stream).1, format!("/{}", i));
+
+            if start.elapsed() > Duration::from_secs(3) {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(300));
+        }
+
+        // Only once it's left idle for long enough
+        let idle = Instant::now();
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+        assert!(idle.elapsed() > Duration::from_millis(800));
+    }
+
+    #[test]
+    fn closes_http_1_0_connections_unless_asked_to_keep_them() {
+        let address = start_server(Config::default());
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(stream, "GET /a HTTP/1.0\r\n\r\n").unwrap();
+
+        let (head, body) = read_response(&mut stream);
+        assert!(head.contains("\r\nConnection: close\r\n"), "{}", head);
+        assert_eq!(body, "/a");
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        for path in ["/a", "/b"] {
+            write!(stream, "GET {} HTTP/1.0\r\nConnection: keep-alive\r\n\r\n", path).unwrap();
+
+            let (head, body) = read_response(&mut stream);
+            assert!(head.contains("\r\nConnection: keep-alive\r\n"), "{}", head);
+            assert_eq!(body, path);
+        }
+    }
+
+    #[test]
+    fn counts_down_the_requests_left_on_a_connection() {
+        let address = start_server(Config {
+            keep_alive_timeout: Duration::from_secs(7),
+            max_requests: 3,
+            ..Config::default()
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        for max in [2, 1] {
+            write!(stream, "GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+
+            let head = read_response(&mut stream).0;
+            assert!(head.contains("\r\nConnection: keep-alive\r\n"), "{}", head);
+            assert!(head.contains(&format!("\r\nKeep-Alive: timeout=7, max={}\r\n", max)), "{}", head);
+        }
+
+        write!(stream, "GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+
+        let head = read_response(&mut stream).0;
+        assert!(head.contains("\r\nConnection: close\r\n"), "{}", head);
+        assert!(!head.contains("Keep-Alive"), "{}", head);
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn turns_connections_away_past_the_limit() {
+        let address = start_server(Config {
+            max_connections: 1,
+            ..Config::default()
+        });
+
+        let mut first = TcpStream::connect(address).unwrap();
+        write!(first, "GET /first HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut first).1, "/first");
+
+        // Its request is never read, which mustn't cost it the response
+        let mut second = TcpStream::connect(address).unwrap();
+        write!(second, "GET /second HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+
+        let mut response = String::new();
+        second.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\nRetry-After: "), "{:?}", response);
+
+        // There's room again once the server has noticed the first one is gone
+        drop(first);
+
+        let start = Instant::now();
+
+        loop {
+            let mut third = TcpStream::connect(address).unwrap();
+            write!(third, "GET /third HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+
+            let (head, body) = read_response(&mut third);
+
+            if head.starts_with("HTTP/1.1 200 ") {
+                assert_eq!(body, "/third");
+                break;
+            }
+
+            assert!(start.elapsed() < Duration::from_secs(5), "{:?}", head);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn rate_limits_requests() {
+        let address = start_server(Config {
+            rate_limit: 0.1,
+            rate_limit_burst: 2,
+            ..Config::default()
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+
+        for i in 0..3 {
+            write!(stream, "GET /{} HTTP/1.1\r\nHost: x\r\n\r\n", i).unwrap();
+        }
+
+        assert_eq!(read_response(&mut stream).1, "/0");
+        assert_eq!(read_response(&mut stream).1, "/1");
+
+        let (head, _) = read_response(&mut stream);
+        assert!(head.starts_with("HTTP/1.1 429 Too Many Requests\r\n"), "{:?}", head);
+        assert!(head.contains("\r\nRetry-After: 10\r\n"), "{:?}", head);
+        assert!(head.contains("\r\nRateLimit-Limit: 2\r\n"), "{:?}", head);
+        assert!(head.contains("\r\nRateLimit-Remaining: 0\r\n"), "{:?}", head);
+        assert!(head.contains("\r\nRateLimit-Reset: 20\r\n"), "{:?}", head);
+
+        // Limits are per client, but everything here comes from localhost
+        let mut other = TcpStream::connect(address).unwrap();
+        write!(other, "GET /other HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert!(read_response(&mut other).0.starts_with("HTTP/1.1 429 "));
+    }
+
+    #[test]
+    fn caps_connections_per_client() {
+        let address = start_server(Config {
+            max_connections_per_ip: 1,
+            ..Config::default()
+        });
+
+        let mut first = TcpStream::connect(address).unwrap();
+        write!(first, "GET /first HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut first).1, "/first");
+
+        let mut second = TcpStream::connect(address).unwrap();
+        write!(second, "GET /second HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+
+        let mut response = String::new();
+        second.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 429 Too Many Requests\r\n"), "{:?}", response);
+        assert!(response.contains("\r\nRetry-After: 5\r\n"), "{:?}", response);
+        assert!(response.contains("\r\nRateLimit-Limit: 1\r\n"), "{:?}", response);
+        assert!(response.contains("\r\nRateLimit-Remaining: 0\r\n"), "{:?}", response);
+        assert!(response.contains("\r\nRateLimit-Reset: 5\r\n"), "{:?}", response);
+        assert!(response.contains("\r\nConnection: close\r\n"), "{:?}", response);
+
+        // The first one is still served
+        write!(first, "GET /again HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut first).1, "/again");
+    }
+
+    #[test]
+    fn publishes_one_copy_to_each_subscriber() {
+        let server = testing::server(Config::default());
+        let publisher = server.publisher();
+        let address = testing::start(server.with_websocket(subscribe_to_path), echo_path);
+
+        let mut first = websocket(address, "/news");
+        let mut second = websocket(address, "/news");
+        let mut other = websocket(address, "/weather");
+
+        assert!(publisher.publish("/news", text("headline")));
+        assert!(publisher.publish("/all", text("marker")));
+
+        // Anything delivered twice would show up before the marker
+        for stream in [&mut first, &mut second] {
+            assert_eq!(read_text(stream), "headline");
+            assert_eq!(read_text(stream), "marker");
+        }
+
+        assert_eq!(read_text(&mut other), "marker");
+    }
+
+    #[test]
+    fn publishes_across_event_loops() {
+        // Taken before the config (and with it the number of loops) is set
+        let server = Server::bind(Ipv4Addr::LOCALHOST.into(), 0, Logger::new(LogLevel::Error)).unwrap();
+        let publisher = server.publisher();
+
+        let server = server.with_config(Config { workers: 4, ..Config::default() }).unwrap();
+        let address = testing::start(server.with_websocket(subscribe_to_path), echo_path);
+
+        // Connections are handed out in turn, so these are spread over every loop
+        let mut streams: Vec<TcpStream> = (0..8).map(|i| websocket(address, &format!("/{}", i))).collect();
+
+        assert!(publisher.publish("/all", text("everyone")));
+        assert!(publisher.publish("/5", text("just one")));
+        assert!(publisher.publish("/all", text("marker")));
+
+        for (i, stream) in streams.iter_mut().enumerate() {
+            assert_eq!(read_text(stream), "everyone");
+
+            if i == 5 {
+                assert_eq!(read_text(stream), "just one");
+            }
+
+            assert_eq!(read_text(stream), "marker");
+        }
+    }
+
+    #[test]
+    fn answers_pipelined_requests_in_order_on_every_loop() {
+        let address = start_server(Config {
+            workers: 4,
+            ..Config::default()
+        });
+
+        let mut streams: Vec<TcpStream> = (0..8).map(|_| TcpStream::connect(address).unwrap()).collect();
+
+        for (i, stream) in streams.iter_mut().enumerate() {
+            let requests: String = (0..50)
+                .map(|j| format!("GET /{}/{} HTTP/1.1\r\nHost: x\r\n\r\n", i, j))
+                .collect();
+
+            stream.write_all(requests.as_bytes()).unwrap();
+        }
+
+        for (i, stream) in streams.iter_mut().enumerate() {
+            for j in 0..50 {
+                assert_eq!(read_response(stream).1, format!("/{}/{}", i, j));
+            }
+        }
+    }
+
+    #[test]
+    fn unsubscribed_clients_get_nothing() {
+        let server = testing::server(Config::default());
+        let publisher = server.publisher();
+        let address = testing::start(server.with_websocket(subscribe_to_path), echo_path);
+
+        let mut leaving = websocket(address, "/news");
+        send_text(&mut leaving, "leave");
+        assert_eq!(read_text(&mut leaving), "left");
+
+        assert!(publisher.publish("/news", text("missed")));
+        assert!(publisher.publish("/all", text("marker")));
+        assert_eq!(read_text(&mut leaving), "marker");
+    }
+
+    #[test]
+    fn drops_slow_subscribers_without_stalling_the_rest() {
+        const MESSAGES: usize = 512;
+        const SIZE: usize = 64 * 1024;
+
+        let server = testing::server(Config {
+            max_send_queue: 4 * SIZE,
+            ..Config::default()
+        });
+
+        let publisher = server.publisher();
+        let address = testing::start(server.with_websocket(subscribe_to_path), echo_path);
+
+        // Never reads, so what's published to it piles up past the limit
+        let mut slow = websocket(address, "/feed");
+        let mut fast = websocket(address, "/feed");
+        fast.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+
+        for _ in 0..MESSAGES {
+            assert!(publisher.publish("/feed", Message::Binary(vec![7; SIZE])));
+            assert_eq!(read_frame(&mut fast), (0x2, vec![7; SIZE]));
+        }
+
+        // The slow one was cut off after what fit in its socket & queue
+        slow.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+
+        let mut received = vec![];
+        let _ = slow.read_to_end(&mut received);
+        assert!(received.len() < MESSAGES * SIZE / 2, "{} bytes", received.len());
+    }
+
+    #[test]
+    fn streams_published_events() {
+        let server = testing::server(Config::default());
+        let publisher = server.publisher();
+        let address = testing::start(server, stream_path);
+
+        let mut stream = event_stream(address, "/feed", None);
+        let mut other = event_stream(address, "/other", None);
+
+        assert!(publisher.send_event("/feed", event(1)));
+        assert!(publisher.send_event("/other", event(2)));
+
+        assert_eq!(read_record(&mut stream), "id: 1\ndata: line 1\ndata: line two\n\n");
+        assert_eq!(read_record(&mut other), "id: 2\ndata: line 2\ndata: line two\n\n");
+    }
+
+    #[test]
+    fn replays_missed_events_on_reconnect() {
+        // A single event loop, so that the history the reconnecting client gets is the one the
+        // watcher shows to be up to date
+        let server = testing::server(Config {
+            workers: 1,
+            ..Config::default()
+        });
+
+        let publisher = server.publisher();
+        let address = testing::start(server, stream_path);
+
+        let mut watcher = event_stream(address, "/feed", None);
+        let mut stream = event_stream(address, "/feed", None);
+
+        for id in 1..=2 {
+            assert!(publisher.send_event("/feed", event(id)));
+            assert_eq!(read_record(&mut watcher), read_record(&mut stream));
+        }
+
+        // Missed while the client was away
+        drop(stream);
+
+        for id in 3..=4 {
+            assert!(publisher.send_event("/feed", event(id)));
+            read_record(&mut watcher);
+        }
+
+        let mut stream = event_stream(address, "/feed", Some("2"));
+        assert_eq!(read_record(&mut stream), "id: 3\ndata: line 3\ndata: line two\n\n");
+        assert_eq!(read_record(&mut stream), "id: 4\ndata: line 4\ndata: line two\n\n");
+
+        // Then it's back to live events
+        assert!(publisher.send_event("/feed", event(5)));
+        assert_eq!(read_record(&mut stream), "id: 5\ndata: line 5\ndata: line two\n\n");
+
+        // An id that's not in the history anymore gets everything that's left
+        let mut lost = event_stream(address, "/feed", Some("unknown"));
+
+        for id in 1..=5 {
+            assert_eq!(read_record(&mut lost), format!("id: {}\ndata: line {}\ndata: line two\n\n", id, id));
+        }
+    }
+
+    #[test]
+    fn answers_head_requests_for_event_streams_with_just_the_head() {
+        let server = testing::server(Config::default());
+        let address = testing::start(server, stream_path);
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // Nothing is streamed, so the connection is free for the next request
+        for _ in 0..2 {
+            write!(stream, "HEAD /feed HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+
+            let head = read_head(&mut stream);
+            assert!(head.starts_with("HTTP/1.1 200 Ok\r\n"), "{}", head);
+            assert!(head.contains("\r\nContent-Type: text/event-stream\r\n"), "{}", head);
+        }
+    }
+
+    #[test]
+    fn keeps_idle_event_streams_alive() {
+        let server = testing::server(Config {
+            sse_keepalive: Duration::from_millis(200),
+            ..Config::default()
+        });
+
+        let address = testing::start(server, stream_path);
+        let mut stream = event_stream(address, "/quiet", None);
+
+        for _ in 0..2 {
+            assert_eq!(read_record(&mut stream), ": keepalive\n\n");
+        }
+    }
 }
@@ -0,0 +1,19 @@
+// ws2's server, for embedding it with handlers of your own. The `ws2` binary is a static file
+// server built on it.
+
+mod client;
+pub mod config;
+mod deflate;
+pub mod http;
+pub mod logging;
+pub mod path;
+mod ratelimit;
+mod redirect;
+pub mod request;
+pub mod response;
+pub mod sse;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "tls")]
+mod tls;
+pub mod websocket;
@@ -39,9 +39,9 @@ impl LogLevel {
     }
 }
 
-impl ToString for LogLevel {
-    fn to_string(&self) -> String {
-        format!("{}[{:?}]\x1b[0m", self.color(), self)
+impl Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}[{:?}]\x1b[0m", self.color(), self)
     }
 }
 
@@ -1,15 +1,15 @@
-use std::{fs, io::ErrorKind, process::exit, rc::Rc};
+use std::{fs::File, io::ErrorKind, process::exit, sync::Arc};
 
-mod client;
-mod config;
-mod http;
-mod logging;
-mod response;
-
-use config::Config;
-use httparse::Request;
-use logging::{Logger, LogLevel};
-use response::{Builder, Response, Status};
+use ws2::{
+    config::{self, Config},
+    http,
+    log,
+    logging::{Logger, LogLevel},
+    path,
+    request::{Method, Request},
+    response::{self, Body, Builder, Response, Status},
+    websocket::{self, Message, WebSocket}
+};
 
 
 fn main() {
@@ -23,56 +23,229 @@ fn main() {
         }
     };
 
-    let server = http::Server::bind(cfg.ip, cfg.port, logger.clone());
+    let server = http::Server::bind(cfg.ip, cfg.port, logger.clone())
+        .and_then(|server| server.with_config(cfg));
 
     match server {
         Ok(server) => server
-            .with_config(cfg)
+            .with_websocket(on_websocket)
             .listen(on_request),
         Err(e) => log!(logger, LogLevel::Error, "Server error: {}", e)
     }
 }
 
 
-fn on_request(request: Request, config: Rc<Config>, logger: Logger) -> Response {
-    log!(logger, LogLevel::Info, "Client request: {} {}", request.method.unwrap_or(""), request.path.unwrap_or(""));
+fn on_request(request: Request, config: Arc<Config>, logger: Logger) -> Response {
+    log!(logger, LogLevel::Info, "Client request from {}: {} {}", request.peer, request.method, request.target);
 
     match request.method {
-        Some("GET") => {
-            match request.path {
-                Some(path) => {
-                    let mut path = config.directory.join(&path[1..]);
+        // HEAD responses are stripped of their body when they're sent
+        Method::Get | Method::Head => {
+            let mut path = match path::decode(request.raw_path()) {
+                Ok(path) => path,
+                Err(e) => {
+                    log!(logger, LogLevel::Warning, "Bad request path {:?}: {}", request.target, e);
+                    return Response::text(Status::BadRequest, "400 Bad Request");
+                }
+            };
+
+            // Directories are served by their index page. Files don't need an extension, ACME
+            // challenge tokens for one never have one.
+            if config.directory.join(&path).is_dir() {
+                path.push("index.html");
+            }
 
-                    if path.extension().is_none() {
-                        path.push("index.html");
-                    }
+            let body = path::resolve(&config.directory, &path, config.follow_symlinks)
+                .and_then(File::open)
+                .and_then(Body::file);
+
+            match body {
+                Ok(body) => {
+                    let mut builder = Builder::with_status(Status::Ok);
 
-                    match fs::read(&path) {
-                        Ok(data) => {
-                            let mut builder = Builder::with_status(Status::Ok);
-
-                            if let Some(mime) = response::mime_from_path(&path) {
-                                builder = builder.add_header("Content-Type", mime);
-                            }
-
-                            builder.set_body(data)
-                                .build()
-                        },
-
-                        Err(e) if e.kind() == ErrorKind::NotFound => {
-                            Response::text(Status::NotFound, "404 Not Found")
-                        },
-                        Err(e) => {
-                            log!(logger, LogLevel::Error, "Error serving {:?}: {}", path, e);
-                            Response::text(Status::InternalServerError, "500 Internal Server Error")
-                        }
+                    if let Some(mime) = response::mime_from_path(&path) {
+                        builder = builder.add_header("Content-Type", mime);
                     }
+
+                    builder.set_body(body)
+                        .build()
                 },
 
-                None => Response::text(Status::BadRequest, "400 Bad Request")
+                Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory | ErrorKind::InvalidInput) => {
+                    Response::text(Status::NotFound, "404 Not Found")
+                },
+                Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                    log!(logger, LogLevel::Warning, "Refused to serve {:?}: {}", path, e);
+                    Response::text(Status::Forbidden, "403 Forbidden")
+                },
+                Err(e) => {
+                    log!(logger, LogLevel::Error, "Error serving {:?}: {}", path, e);
+                    Response::text(Status::InternalServerError, "500 Internal Server Error")
+                }
             }
         },
 
+        Method::Options => {
+            Builder::with_status(Status::NoContent)
+                .add_header("Allow", response::ALLOWED_METHODS)
+                .build()
+        },
+
         _ => Response::text(Status::MethodNotAllowed, "405 Method Not Allowed")
     }
 }
+
+
+// Echo every message back to the client that sent it
+fn on_websocket(socket: &mut WebSocket, event: websocket::Event, _config: Arc<Config>, logger: Logger) {
+    match event {
+        websocket::Event::Open(request) => {
+            log!(logger, LogLevel::Info, "WebSocket {} opened by {}: {}", socket.id(), socket.peer(), request.target);
+        },
+
+        websocket::Event::Message(message) => {
+            if let Message::Text(text) = &message {
+                log!(logger, LogLevel::Debug, "WebSocket {} message: {}", socket.id(), text);
+            }
+
+            socket.send(message);
+        },
+
+        websocket::Event::Close(code, reason) => {
+            log!(logger, LogLevel::Info, "WebSocket {} closed: {} {}", socket.id(), code, reason);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{Read, Write},
+        net::{Ipv4Addr, SocketAddr, TcpStream},
+        path::{Path, PathBuf},
+        thread,
+        time::Duration
+    };
+
+    use ws2::{config::Config, http::Server, logging::{LogLevel, Logger}};
+
+    use super::on_request;
+
+
+    // A fresh directory holding "index.html", for the test to remove
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("ws2-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("index.html"), "<p>hello</p>").unwrap();
+
+        directory
+    }
+
+    // A server on a free port of localhost, using `config` with `directory` as the root
+    fn server(directory: &Path, config: Config) -> Server {
+        let server = Server::bind(Ipv4Addr::LOCALHOST.into(), 0, Logger::new(LogLevel::Error)).unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        server.with_config(Config { ip: Ipv4Addr::LOCALHOST.into(), port, directory: directory.to_path_buf(), ..config })
+            .unwrap()
+    }
+
+    // Serve with `on_request` on its own thread, returning the address it listens on
+    fn start(server: Server) -> SocketAddr {
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.listen(on_request));
+
+        address
+    }
+
+    // Send a request on its own connection, returning everything sent back
+    fn request(address: SocketAddr, method: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n", method, path).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+
+    #[test]
+    fn answers_head_with_the_length_of_get() {
+        let directory = directory("main-head");
+        let address = start(server(&directory, Config::default()));
+
+        let get = request(address, "GET", "/");
+        let head = request(address, "HEAD", "/");
+
+        assert!(get.contains("\r\nContent-Length: 12\r\n"), "{}", get);
+        assert!(get.ends_with("\r\n\r\n<p>hello</p>"), "{}", get);
+
+        assert!(head.starts_with("HTTP/1.1 200 Ok\r\n"), "{}", head);
+        assert!(head.contains("\r\nContent-Length: 12\r\n"), "{}", head);
+        assert!(head.contains("\r\nContent-Type: text/html"), "{}", head);
+        assert!(head.ends_with("\r\n\r\n"), "{}", head);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn lists_allowed_methods() {
+        let directory = directory("main-allow");
+        let address = start(server(&directory, Config::default()));
+
+        let options = request(address, "OPTIONS", "/");
+        assert!(options.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", options);
+        assert!(options.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"), "{}", options);
+
+        let delete = request(address, "DELETE", "/");
+        assert!(delete.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{}", delete);
+        assert!(delete.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"), "{}", delete);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn redirects_to_https_apart_from_acme_challenges() {
+        let directory = directory("main-redirect");
+        let challenge = directory.join(".well-known/acme-challenge");
+        fs::create_dir_all(&challenge).unwrap();
+        fs::write(challenge.join("token"), "token contents").unwrap();
+
+        // Kept out of the directory that's served
+        let generated = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let (cert, key) = (directory.with_extension("cert.pem"), directory.with_extension("key.pem"));
+        fs::write(&cert, generated.cert.pem()).unwrap();
+        fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+
+        let server = server(&directory, Config {
+            tls_cert: Some(cert.clone()),
+            tls_key: Some(key.clone()),
+            redirect_port: Some(0),
+            ..Config::default()
+        });
+
+        let redirect = server.redirect_addr().unwrap().unwrap();
+        let address = start(server);
+
+        let get = request(redirect, "GET", "/a%20b?c=d");
+        assert!(get.starts_with("HTTP/1.1 301 Moved Permanently\r\n"), "{}", get);
+        assert!(get.contains(&format!("\r\nLocation: https://x:{}/a%20b?c=d\r\n", address.port())), "{}", get);
+
+        let post = request(redirect, "POST", "/form");
+        assert!(post.starts_with("HTTP/1.1 308 Permanent Redirect\r\n"), "{}", post);
+        assert!(post.contains(&format!("\r\nLocation: https://x:{}/form\r\n", address.port())), "{}", post);
+
+        let token = request(redirect, "GET", "/.well-known/acme-challenge/token");
+        assert!(token.starts_with("HTTP/1.1 200 Ok\r\n"), "{}", token);
+        assert!(token.ends_with("\r\n\r\ntoken contents"), "{}", token);
+
+        fs::remove_dir_all(directory).unwrap();
+        fs::remove_file(cert).unwrap();
+        fs::remove_file(key).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+// Turn request paths into paths on the filesystem
+
+use std::{ffi::OsString, fmt::Display, io, path::{Path, PathBuf}};
+
+use percent_encoding::percent_decode_str;
+
+
+#[derive(Debug)]
+pub enum Error {
+    NotAbsolute, // Doesn't start with '/'
+    EncodedSeparator, // A segment decodes to something containing '/' or '\'
+    Nul, // A segment decodes to something containing a NUL byte
+    #[cfg_attr(unix, allow(dead_code))]
+    InvalidUnicode // Can't be represented as an OsString on this platform
+}
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::NotAbsolute => f.write_str("Path doesn't start with '/'"),
+            Error::EncodedSeparator => f.write_str("Path segment contains an encoded separator"),
+            Error::Nul => f.write_str("Path contains a NUL byte"),
+            Error::InvalidUnicode => f.write_str("Path isn't valid unicode")
+        }
+    }
+}
+
+
+// Percent-decode a request path ("/some%20dir/../file.txt") into a path relative to the document
+// root ("file.txt"). Dot segments are resolved as in RFC 3986 5.2.4, so the result never climbs
+// above the root.
+pub fn decode(path: &str) -> Result<PathBuf, Error> {
+    let path = path.strip_prefix('/').ok_or(Error::NotAbsolute)?;
+    let mut segments: Vec<Vec<u8>> = vec![];
+
+    for segment in path.split('/') {
+        let segment: Vec<u8> = percent_decode_str(segment).collect();
+
+        // Separators have to be literal, otherwise "..%2F.." would slip past the dot segment
+        // handling below
+        if segment.iter().any(|&b| b == b'/' || b == b'\\') {
+            return Err(Error::EncodedSeparator);
+        }
+
+        if segment.contains(&0) {
+            return Err(Error::Nul);
+        }
+
+        match segment.as_slice() {
+            b"" | b"." => (),
+            b".." => { segments.pop(); },
+            _ => segments.push(segment)
+        }
+    }
+
+    let mut decoded = PathBuf::new();
+
+    for segment in segments {
+        decoded.push(os_string(segment)?);
+    }
+
+    Ok(decoded)
+}
+
+
+// Find the file `relative` (from `decode`) refers to inside `root`. Symlinks that lead outside of
+// `root` are refused with `PermissionDenied` unless `follow_symlinks` is set.
+pub fn resolve(root: &Path, relative: &Path, follow_symlinks: bool) -> io::Result<PathBuf> {
+    let root = root.canonicalize()?;
+    let path = root.join(relative).canonicalize()?;
+
+    if path.starts_with(&root) || follow_symlinks {
+        Ok(path)
+    }
+    else {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "Path leads outside the document root"))
+    }
+}
+
+
+#[cfg(unix)]
+fn os_string(bytes: Vec<u8>) -> Result<OsString, Error> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn os_string(bytes: Vec<u8>) -> Result<OsString, Error> {
+    String::from_utf8(bytes)
+        .map(OsString::from)
+        .map_err(|_| Error::InvalidUnicode)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io, path::{Path, PathBuf}};
+
+    use super::{decode, resolve, Error};
+
+
+    // A fresh directory under the system temp dir, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("ws2-{}-{}", name, std::process::id()));
+
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+
+    #[test]
+    fn decodes_segments() {
+        assert_eq!(decode("/").unwrap(), PathBuf::new());
+        assert_eq!(decode("/some%20dir/f%C3%BC.txt").unwrap(), Path::new("some dir/f\u{fc}.txt"));
+        assert_eq!(decode("//a///b/").unwrap(), Path::new("a/b"));
+    }
+
+    #[test]
+    fn resolves_dot_segments_within_root() {
+        assert_eq!(decode("/a/./b/../c").unwrap(), Path::new("a/c"));
+        assert_eq!(decode("/../../etc/passwd").unwrap(), Path::new("etc/passwd"));
+        assert_eq!(decode("/%2e%2e/%2E%2E/etc/passwd").unwrap(), Path::new("etc/passwd"));
+        assert_eq!(decode("/a/.%2e/.%2E/b").unwrap(), Path::new("b"));
+    }
+
+    #[test]
+    fn rejects_encoded_separators() {
+        assert!(matches!(decode("/..%2f..%2fetc/passwd"), Err(Error::EncodedSeparator)));
+        assert!(matches!(decode("/..%5c..%5cetc/passwd"), Err(Error::EncodedSeparator)));
+        assert!(matches!(decode("/..\\..\\etc/passwd"), Err(Error::EncodedSeparator)));
+    }
+
+    #[test]
+    fn rejects_nul_and_relative_paths() {
+        assert!(matches!(decode("/index.html%00.txt"), Err(Error::Nul)));
+        assert!(matches!(decode("index.html"), Err(Error::NotAbsolute)));
+        assert!(matches!(decode("*"), Err(Error::NotAbsolute)));
+    }
+
+    #[test]
+    fn resolves_files_inside_root() {
+        let dir = TempDir::new("resolve-inside");
+        fs::create_dir(dir.0.join("sub")).unwrap();
+        fs::write(dir.0.join("sub/file.txt"), "hi").unwrap();
+
+        let path = resolve(&dir.0, &decode("/sub/../sub/file.txt").unwrap(), false).unwrap();
+        assert_eq!(path, dir.0.canonicalize().unwrap().join("sub/file.txt"));
+
+        let missing = resolve(&dir.0, &decode("/nope.txt").unwrap(), false);
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_outside_root_need_permission() {
+        let outside = TempDir::new("resolve-outside");
+        let root = TempDir::new("resolve-root");
+
+        fs::write(outside.0.join("secret.txt"), "secret").unwrap();
+        fs::write(root.0.join("public.txt"), "public").unwrap();
+        std::os::unix::fs::symlink(outside.0.join("secret.txt"), root.0.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink(root.0.join("public.txt"), root.0.join("inner.txt")).unwrap();
+
+        let link = decode("/link.txt").unwrap();
+        assert_eq!(resolve(&root.0, &link, false).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(resolve(&root.0, &link, true).unwrap(), outside.0.canonicalize().unwrap().join("secret.txt"));
+
+        // Symlinks that stay inside the root are always fine
+        let inner = decode("/inner.txt").unwrap();
+        assert_eq!(resolve(&root.0, &inner, false).unwrap(), root.0.canonicalize().unwrap().join("public.txt"));
+    }
+}
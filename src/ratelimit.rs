@@ -0,0 +1,270 @@
+// Per-client limits on requests & simultaneous connections, shared by all event loops
+
+use std::{collections::HashMap, net::{IpAddr, Ipv6Addr}, sync::Mutex, time::{Duration, Instant}};
+
+use crate::{config::Config, response::{Builder, Response, Status}};
+
+
+// There's no telling when a client over its connection limit closes one, so it's asked to come
+// back after this long
+const CONNECTION_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+
+// Requests from a client get a token each, which refill at a steady rate up to a burst
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant
+}
+
+
+#[derive(Debug, Default)]
+struct State {
+    buckets: HashMap<IpAddr, Bucket>,
+    connections: HashMap<IpAddr, usize>,
+    prune_at: usize // Number of buckets at which the full ones get dropped
+}
+
+
+// Clients are told apart by IP address, IPv6 ones by their prefix since a single host usually
+// gets a whole /64 to pick addresses from
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64, // Tokens per second, no limit on requests if 0
+    burst: f64,
+    max_connections: usize, // Per client, no limit if 0
+    ipv6_prefix: u8,
+    state: Mutex<State>
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config) -> Self {
+        RateLimiter {
+            rate: config.rate_limit,
+            burst: config.rate_limit_burst.max(1) as f64,
+            max_connections: config.max_connections_per_ip,
+            ipv6_prefix: config.ipv6_prefix,
+            state: Mutex::new(State::default())
+        }
+    }
+
+    // Count a new connection from `ip`, unless it already has as many as it's allowed
+    pub fn connect(&self, ip: IpAddr) -> Result<(), Limited> {
+        if self.max_connections == 0 {
+            return Ok(());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let count = state.connections.entry(client_key(ip, self.ipv6_prefix)).or_default();
+
+        if *count >= self.max_connections {
+            return Err(Limited {
+                limit: self.max_connections as u64,
+                retry_after: CONNECTION_RETRY_AFTER,
+                reset: CONNECTION_RETRY_AFTER
+            });
+        }
+
+        *count += 1;
+        Ok(())
+    }
+
+    // A connection counted by `connect` has been closed
+    pub fn disconnect(&self, ip: IpAddr) {
+        if self.max_connections == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let key = client_key(ip, self.ipv6_prefix);
+
+        if let Some(count) = state.connections.get_mut(&key) {
+            *count -= 1;
+
+            if *count == 0 {
+                state.connections.remove(&key);
+            }
+        }
+    }
+
+    // Take a token for a request from `ip`, or find out how long it has to wait for one
+    pub fn check(&self, ip: IpAddr, now: Instant) -> Result<(), Limited> {
+        if self.rate == 0.0 {
+            return Ok(());
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        // A full bucket is no different from a missing one, so those can go once there's many
+        if state.buckets.len() >= state.prune_at {
+            let (rate, burst) = (self.rate, self.burst);
+            state.buckets.retain(|_, bucket| bucket.tokens + now.saturating_duration_since(bucket.updated).as_secs_f64() * rate < burst);
+            state.prune_at = (state.buckets.len() * 2).max(1024);
+        }
+
+        let bucket = state.buckets.entry(client_key(ip, self.ipv6_prefix)).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        Err(Limited {
+            limit: self.burst as u64,
+            retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate),
+            reset: Duration::from_secs_f64((self.burst - bucket.tokens) / self.rate)
+        })
+    }
+}
+
+
+// A request over the rate limit, or a connection over the per-client cap
+#[derive(Debug)]
+pub struct Limited {
+    limit: u64, // Requests allowed in a burst, or connections at once
+    retry_after: Duration, // Until the next request is allowed
+    reset: Duration // Until the whole burst is available again
+}
+
+impl Limited {
+    // The 429 response, along with when to try again
+    pub fn response(&self) -> Response {
+        Builder::with_status(Status::TooManyRequests)
+            .add_header("Retry-After", whole_secs(self.retry_after).to_string())
+            .add_header("RateLimit-Limit", self.limit.to_string())
+            .add_header("RateLimit-Remaining", "0")
+            .add_header("RateLimit-Reset", whole_secs(self.reset).to_string())
+            .set_body(Status::TooManyRequests.as_str().as_bytes().to_vec())
+            .build()
+    }
+}
+
+
+// The address limits are kept under for `ip`. IPv4-mapped IPv6 addresses count as the IPv4 address
+// they stand for.
+fn client_key(ip: IpAddr, ipv6_prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(ipv6_prefix.min(128))).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+            }
+        }
+    }
+}
+
+
+// Headers only take whole seconds, which are rounded up so clients don't come back too early
+fn whole_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, time::{Duration, Instant}};
+
+    use super::{client_key, RateLimiter};
+    use crate::config::Config;
+
+
+    fn limiter(rate: f64, burst: u32, max_connections: usize) -> RateLimiter {
+        RateLimiter::new(&Config {
+            rate_limit: rate,
+            rate_limit_burst: burst,
+            max_connections_per_ip: max_connections,
+            ..Config::default()
+        })
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+
+    #[test]
+    fn groups_ipv6_clients_by_prefix() {
+        assert_eq!(client_key(ip("2001:db8:1:2:aaaa::1"), 64), ip("2001:db8:1:2::"));
+        assert_eq!(client_key(ip("2001:db8:1:2:aaaa::1"), 48), ip("2001:db8:1::"));
+        assert_eq!(client_key(ip("2001:db8::1"), 128), ip("2001:db8::1"));
+        assert_eq!(client_key(ip("2001:db8::1"), 0), ip("::"));
+        assert_eq!(client_key(ip("::ffff:192.0.2.1"), 64), ip("192.0.2.1"));
+        assert_eq!(client_key(ip("192.0.2.1"), 64), ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn allows_bursts_then_refills_at_the_rate() {
+        let limiter = limiter(2.0, 3, 0);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check(ip("192.0.2.1"), now).is_ok());
+        }
+
+        let limited = limiter.check(ip("192.0.2.1"), now).unwrap_err();
+        assert_eq!(limited.retry_after, Duration::from_millis(500));
+        assert_eq!(limited.reset, Duration::from_millis(1500));
+
+        // Other clients have their own buckets, IPv6 ones per /64
+        assert!(limiter.check(ip("192.0.2.2"), now).is_ok());
+        assert!(limiter.check(ip("2001:db8::1"), now).is_ok());
+
+        // One token back after half a second
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.check(ip("192.0.2.1"), later).is_ok());
+        assert!(limiter.check(ip("192.0.2.1"), later).is_err());
+    }
+
+    #[test]
+    fn responds_with_when_to_retry() {
+        let limiter = limiter(0.5, 1, 0);
+        let now = Instant::now();
+
+        assert!(limiter.check(ip("192.0.2.1"), now).is_ok());
+
+        let response = limiter.check(ip("192.0.2.1"), now).unwrap_err().response();
+        let head = String::from_utf8(response.encode(1, true).next().unwrap().unwrap()).unwrap();
+
+        assert!(head.starts_with("HTTP/1.1 429 Too Many Requests\r\n"), "{:?}", head);
+        assert!(head.contains("\r\nRetry-After: 2\r\n"));
+        assert!(head.contains("\r\nRateLimit-Limit: 1\r\n"));
+        assert!(head.contains("\r\nRateLimit-Remaining: 0\r\n"));
+        assert!(head.contains("\r\nRateLimit-Reset: 2\r\n"));
+    }
+
+    #[test]
+    fn caps_connections_per_client() {
+        let limiter = limiter(0.0, 1, 2);
+
+        assert!(limiter.connect(ip("2001:db8::1")).is_ok());
+        assert!(limiter.connect(ip("2001:db8::2")).is_ok());
+        assert!(limiter.connect(ip("2001:db8:0:1::1")).is_ok());
+
+        let limited = limiter.connect(ip("2001:db8::3")).unwrap_err();
+        assert_eq!(limited.limit, 2);
+        assert_eq!(limited.retry_after, Duration::from_secs(5));
+
+        limiter.disconnect(ip("2001:db8::1"));
+        assert!(limiter.connect(ip("2001:db8::3")).is_ok());
+    }
+
+    #[test]
+    fn no_limits_by_default() {
+        let limiter = RateLimiter::new(&Config::default());
+        let now = Instant::now();
+
+        for _ in 0..1000 {
+            assert!(limiter.check(ip("192.0.2.1"), now).is_ok());
+            assert!(limiter.connect(ip("192.0.2.1")).is_ok());
+        }
+    }
+}
@@ -0,0 +1,186 @@
+// Sends requests on the plaintext listener over to HTTPS
+
+use crate::{
+    config::Config,
+    path,
+    request::{Method, Request},
+    response::{Builder, Response, Status}
+};
+
+
+// `true` if the request's path falls under one of the configured exemptions, which are handled
+// like any other request instead of being redirected. Both sides are normalized first, so dot
+// segments can't be used to reach outside an exempt directory.
+pub fn is_exempt(request: &Request, config: &Config) -> bool {
+    let Ok(path) = path::decode(request.raw_path()) else {
+        return false;
+    };
+
+    config.redirect_exempt.iter()
+        .filter_map(|exempt| path::decode(exempt).ok())
+        .any(|exempt| path.starts_with(exempt))
+}
+
+
+// Redirect a request to the same URL on HTTPS. GET & HEAD get the widely understood 301, anything
+// else a 308 so the method & body are kept.
+pub fn response(request: &Request, config: &Config) -> Response {
+    let Some(host) = request.headers.get_str("Host").and_then(hostname) else {
+        return Response::text(Status::BadRequest, "400 Bad Request");
+    };
+
+    let mut location = format!("https://{}", host);
+
+    if config.port != 443 {
+        location.push_str(&format!(":{}", config.port));
+    }
+
+    location.push_str(request.raw_path());
+
+    if let Some(query) = request.raw_query() {
+        location.push('?');
+        location.push_str(query);
+    }
+
+    let status = match request.method {
+        Method::Get | Method::Head => Status::MovedPermanently,
+        _ => Status::PermanentRedirect
+    };
+
+    let body = format!("{}\nMoved to {}\n", status.as_str(), location);
+
+    Builder::with_status(status)
+        .add_header("Location", location)
+        .set_body(body.into_bytes())
+        .build()
+}
+
+
+// The hostname from a Host header ("example.com:8080" or "[::1]:8080"), without the port. `None`
+// if it has anything that doesn't belong in a hostname or port, so it's safe to put in a Location
+// header.
+fn hostname(host: &str) -> Option<&str> {
+    let host = host.trim();
+
+    let (name, port) = match host.strip_prefix('[') {
+        Some(rest) => host.split_at(rest.find(']')? + 2),
+        None => host.split_at(host.find(':').unwrap_or(host.len()))
+    };
+
+    let valid_name = !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"-.:[]".contains(&b));
+    let valid_port = port.strip_prefix(':').map_or(port.is_empty(), |port| port.bytes().all(|b| b.is_ascii_digit()));
+
+    (valid_name && valid_port).then_some(name)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::{hostname, is_exempt, response};
+    use crate::{
+        config::Config,
+        request::{Head, Headers, Method, Request},
+        response::Response
+    };
+
+
+    fn request(method: Method, target: &str, host: Option<&str>) -> Request {
+        let mut headers = Headers::default();
+
+        if let Some(host) = host {
+            headers.push(String::from("Host"), host.as_bytes().to_vec());
+        }
+
+        let head = Head { method, target: target.to_string(), version: 1, headers };
+        Request::new(head, vec![], SocketAddr::from((Ipv4Addr::LOCALHOST, 1234)), 1)
+    }
+
+    fn head(response: Response) -> String {
+        String::from_utf8(response.encode(1, true).next().unwrap().unwrap()).unwrap()
+    }
+
+
+    #[test]
+    fn takes_the_port_off_hostnames() {
+        assert_eq!(hostname("example.com"), Some("example.com"));
+        assert_eq!(hostname("example.com:8080"), Some("example.com"));
+        assert_eq!(hostname(" 192.168.1.1:80 "), Some("192.168.1.1"));
+        assert_eq!(hostname("[::1]:8080"), Some("[::1]"));
+        assert_eq!(hostname("[::1]"), Some("[::1]"));
+    }
+
+    #[test]
+    fn rejects_hostnames_unfit_for_a_location() {
+        for host in [
+            "",
+            ":8080",
+            "[::1",
+            "evil.com/path",
+            "user@evil.com",
+            "a\r\nSet-Cookie: x=1",
+            "a b",
+            "[::1]@evil.com",
+            "example.com:80/path",
+            "example.com:80@evil.com",
+            "example.com:8080:1"
+        ] {
+            assert_eq!(hostname(host), None, "{:?}", host);
+        }
+    }
+
+    #[test]
+    fn exempts_paths_only_within_the_prefix() {
+        let config = Config::default();
+        let exempt = |target| is_exempt(&request(Method::Get, target, Some("x")), &config);
+
+        assert!(exempt("/.well-known/acme-challenge/token"));
+        assert!(exempt("/.well-known/%61cme-challenge/token"));
+        assert!(exempt("/other/../.well-known/acme-challenge/token"));
+
+        assert!(!exempt("/.well-known/acme-challenge/../../secret"));
+        assert!(!exempt("/.well-known/acme-challenge-other/token"));
+        assert!(!exempt("/.well-known/acme-challenge%2F..%2F..%2Fsecret"));
+        assert!(!exempt("/secret"));
+    }
+
+    #[test]
+    fn keeps_the_method_for_anything_but_get_and_head() {
+        let config = Config { port: 443, ..Config::default() };
+
+        for (method, status) in [
+            (Method::Get, "301 Moved Permanently"),
+            (Method::Head, "301 Moved Permanently"),
+            (Method::Post, "308 Permanent Redirect"),
+            (Method::Delete, "308 Permanent Redirect")
+        ] {
+            let head = head(response(&request(method, "/", Some("example.com")), &config));
+            assert!(head.starts_with(&format!("HTTP/1.1 {}\r\n", status)), "{}", head);
+        }
+    }
+
+    #[test]
+    fn builds_the_location() {
+        let default_port = Config { port: 443, ..Config::default() };
+        let other_port = Config { port: 8443, ..Config::default() };
+
+        let target = "/some%20dir/file.txt?a=1&b=%20";
+        let location = |host, config| head(response(&request(Method::Get, target, Some(host)), config));
+
+        assert!(location("example.com:80", &default_port).contains("\r\nLocation: https://example.com/some%20dir/file.txt?a=1&b=%20\r\n"));
+        assert!(location("example.com", &other_port).contains("\r\nLocation: https://example.com:8443/some%20dir/file.txt?a=1&b=%20\r\n"));
+        assert!(location("[::1]:80", &other_port).contains("\r\nLocation: https://[::1]:8443/some%20dir/file.txt?a=1&b=%20\r\n"));
+    }
+
+    #[test]
+    fn needs_a_valid_host() {
+        let config = Config::default();
+
+        for host in [None, Some(""), Some("evil.com/x")] {
+            let head = head(response(&request(Method::Get, "/", host), &config));
+            assert!(head.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", head);
+            assert!(!head.contains("Location"));
+        }
+    }
+}
@@ -0,0 +1,806 @@
+// Parse HTTP Requests out of a connection's receive buffer
+
+use std::{collections::HashMap, fmt::Display, net::SocketAddr};
+
+use httparse::EMPTY_HEADER;
+use percent_encoding::percent_decode_str;
+
+use crate::{config::Config, response::Status};
+
+
+// Headers room is made for up front, requests with more are parsed again with room for more
+const MIN_HEADERS: usize = 32;
+
+
+#[derive(Debug)]
+pub enum Error {
+    Malformed(httparse::Error),
+    HeadTooLarge,
+    BadContentLength,
+    ConflictingLength,
+    UnsupportedEncoding,
+    BadChunk,
+    BodyTooLarge
+}
+
+impl Error {
+    // The status to reply with before closing the connection
+    pub fn status(&self) -> Status {
+        match self {
+            Error::Malformed(httparse::Error::TooManyHeaders) | Error::HeadTooLarge => Status::RequestHeaderFieldsTooLarge,
+            Error::Malformed(_) | Error::BadContentLength | Error::ConflictingLength | Error::BadChunk => Status::BadRequest,
+            Error::UnsupportedEncoding => Status::NotImplemented,
+            Error::BodyTooLarge => Status::PayloadTooLarge
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Malformed(e) => write!(f, "Malformed request: {}", e),
+            Error::HeadTooLarge => f.write_str("Request head too large"),
+            Error::BadContentLength => f.write_str("Invalid Content-Length header"),
+            Error::ConflictingLength => f.write_str("Both Content-Length and Transfer-Encoding present"),
+            Error::UnsupportedEncoding => f.write_str("Unsupported Transfer-Encoding"),
+            Error::BadChunk => f.write_str("Malformed chunked body"),
+            Error::BodyTooLarge => f.write_str("Request body too large")
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+    Other(String)
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+            Method::Other(method) => method
+        }
+    }
+}
+
+impl From<&str> for Method {
+    // Methods are case-sensitive, so "get" is an unknown method rather than GET
+    fn from(value: &str) -> Self {
+        match value {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "CONNECT" => Method::Connect,
+            "OPTIONS" => Method::Options,
+            "TRACE" => Method::Trace,
+            "PATCH" => Method::Patch,
+            _ => Method::Other(value.to_string())
+        }
+    }
+}
+
+impl Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+
+// Request headers in the order they were received, looked up case-insensitively
+#[derive(Clone, Debug, Default)]
+pub struct Headers(Vec<(String, Vec<u8>)>);
+
+impl Headers {
+    // Returns the value of the first header matching `name`
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.0.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_slice())
+    }
+
+    // Returns the values of all headers matching `name`
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.0.iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_slice())
+    }
+
+    // Returns the first value matching `name` as a str, if it's valid UTF-8
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        std::str::from_utf8(self.get(name)?).ok()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    // `true` if any comma-separated value of the `name` headers matches `token` (case-insensitive)
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(|&b| b == b','))
+            .any(|value| value.trim_ascii().eq_ignore_ascii_case(token.as_bytes()))
+    }
+
+    pub fn push(&mut self, name: String, value: Vec<u8>) {
+        self.0.push((name, value));
+    }
+
+    #[allow(dead_code)]
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.0.iter().map(|(name, value)| (name.as_str(), value.as_slice()))
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+
+// The request line & headers of a request, as parsed off the wire
+#[derive(Debug)]
+pub struct Head {
+    pub method: Method,
+    pub target: String, // Raw request-target, e.g. "/some%20file.txt?v=2"
+    pub version: u8, // Minor version, HTTP/1.x
+    pub headers: Headers
+}
+
+impl Head {
+    // How the body of this request is delimited
+    fn body_framing(&self) -> Result<Body, Error> {
+        let mut encodings = self.headers.get_all("Transfer-Encoding").peekable();
+
+        if encodings.peek().is_none() {
+            return Ok(Body::Length(self.content_length()?));
+        }
+
+        // A message with both is either broken or an attempt at request smuggling
+        if self.headers.contains("Content-Length") {
+            return Err(Error::ConflictingLength);
+        }
+
+        // Only a plain "chunked" coding is supported, anything layered on top of it isn't
+        let mut codings = encodings
+            .flat_map(|value| value.split(|&b| b == b','))
+            .map(|coding| coding.trim_ascii())
+            .filter(|coding| !coding.is_empty());
+
+        match (codings.next(), codings.next()) {
+            (Some(coding), None) if coding.eq_ignore_ascii_case(b"chunked") => Ok(Body::Chunked(Chunk::Size)),
+            _ => Err(Error::UnsupportedEncoding)
+        }
+    }
+
+    // Length of the body as announced by the Content-Length header(s)
+    fn content_length(&self) -> Result<usize, Error> {
+        let mut length = None;
+
+        for value in self.headers.get_all("Content-Length") {
+            let value = std::str::from_utf8(value).map_err(|_| Error::BadContentLength)?.trim();
+
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(Error::BadContentLength);
+            }
+
+            let value = value.parse().map_err(|_| Error::BadContentLength)?;
+
+            // Repeated headers are only acceptable if they all agree
+            if length.is_some_and(|len| len != value) {
+                return Err(Error::BadContentLength);
+            }
+
+            length = Some(value);
+        }
+
+        Ok(length.unwrap_or(0))
+    }
+}
+
+
+// A complete HTTP Request, as handed to request handlers
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct Request {
+    pub method: Method,
+    pub target: String, // Raw request-target, e.g. "/some%20file.txt?v=2"
+    pub path: String, // Percent-decoded path, without the query
+    pub query: HashMap<String, String>, // Percent-decoded query parameters
+    pub version: u8, // Minor version, HTTP/1.x
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    pub peer: SocketAddr,
+    pub connection: usize // Key of the connection the request came in on
+}
+
+impl Request {
+    pub fn new(head: Head, body: Vec<u8>, peer: SocketAddr, connection: usize) -> Self {
+        let (path, query) = split_target(&head.target);
+
+        Request {
+            method: head.method,
+            path: percent_decode_str(path).decode_utf8_lossy().into_owned(),
+            query: query.map(parse_query).unwrap_or_default(),
+            target: head.target,
+            version: head.version,
+            headers: head.headers,
+            body,
+            peer,
+            connection
+        }
+    }
+
+    // The path part of the request-target, still percent-encoded
+    pub fn raw_path(&self) -> &str {
+        split_target(&self.target).0
+    }
+
+    // The query part of the request-target, still percent-encoded
+    pub fn raw_query(&self) -> Option<&str> {
+        split_target(&self.target).1
+    }
+
+    // Whether the client wants the connection to stay open after this request
+    pub fn keep_alive(&self) -> bool {
+        match self.version {
+            0 => self.headers.has_token("Connection", "keep-alive"),
+            _ => !self.headers.has_token("Connection", "close")
+        }
+    }
+}
+
+
+// Split a request-target into its path and query, dropping the scheme & authority of
+// absolute-form targets ("http://host/path")
+fn split_target(target: &str) -> (&str, Option<&str>) {
+    let target = match target.find("://") {
+        Some(scheme_end) if target.starts_with("http") => {
+            let rest = &target[scheme_end + 3..];
+            rest.find('/').map_or("/", |path_start| &rest[path_start..])
+        },
+        _ => target
+    };
+
+    match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None)
+    }
+}
+
+
+// Parse a query string ("a=1&b=two+words") into a map, later duplicates win
+fn parse_query(query: &str) -> HashMap<String, String> {
+    let decode = |s: &str| percent_decode_str(&s.replace('+', " ")).decode_utf8_lossy().into_owned();
+
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (decode(key), decode(value)),
+            None => (decode(pair), String::new())
+        })
+        .collect()
+}
+
+
+// How a request's body is delimited, and how much of it is left
+#[derive(Debug)]
+enum Body {
+    Length(usize), // Bytes still expected
+    Chunked(Chunk)
+}
+
+// Position within a chunked body
+#[derive(Debug)]
+enum Chunk {
+    Size, // Waiting for a chunk-size line
+    Data(usize), // Bytes left in the current chunk
+    DataEnd, // Waiting for the CRLF after a chunk's data
+    Trailers // Waiting for trailer fields / the final empty line
+}
+
+
+// Per-connection parsing state, holds on to a request's head while its body is still arriving
+#[derive(Debug)]
+pub struct Parser {
+    head: Option<Head>,
+    body: Vec<u8>,
+    framing: Body
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Parser {
+            head: None,
+            body: vec![],
+            framing: Body::Length(0)
+        }
+    }
+}
+
+impl Parser {
+    // Try to parse a complete request from the front of `buffer`, removing the bytes it used.
+    // Returns `Ok(None)` if more data is needed.
+    pub fn parse(&mut self, buffer: &mut Vec<u8>, config: &Config) -> Result<Option<(Head, Vec<u8>)>, Error> {
+        if self.head.is_none() {
+            let (head, len) = match parse_head(buffer, config)? {
+                Some(parsed) => parsed,
+                None => return Ok(None)
+            };
+
+            let framing = head.body_framing()?;
+
+            if matches!(framing, Body::Length(len) if len > config.max_body_size) {
+                return Err(Error::BodyTooLarge);
+            }
+
+            buffer.drain(0..len);
+            self.framing = framing;
+            self.head = Some(head);
+        }
+
+        let head = self.head.as_mut().unwrap();
+
+        let done = match &mut self.framing {
+            Body::Length(remaining) => {
+                let available = (*remaining).min(buffer.len());
+
+                self.body.extend(buffer.drain(0..available));
+                *remaining -= available;
+                *remaining == 0
+            },
+
+            Body::Chunked(chunk) => decode_chunked(chunk, head, &mut self.body, buffer, config)?
+        };
+
+        match done {
+            true => Ok(self.head.take().map(|head| (head, std::mem::take(&mut self.body)))),
+            false => Ok(None)
+        }
+    }
+
+    // Bytes of body received so far, `None` unless the head is in and the body is still arriving
+    pub fn body_received(&self) -> Option<usize> {
+        self.head.as_ref().map(|_| self.body.len())
+    }
+}
+
+
+// Decode as much of a chunked body as `buffer` holds into `body`, returns `true` once the final
+// chunk and trailers have been read
+fn decode_chunked(chunk: &mut Chunk, head: &mut Head, body: &mut Vec<u8>, buffer: &mut Vec<u8>, config: &Config) -> Result<bool, Error> {
+    let mut pos = 0;
+    let mut trailer_len = 0;
+
+    let done = loop {
+        match chunk {
+            Chunk::Size => {
+                let Some(line) = next_line(&buffer[pos..], config.max_head_size)? else { break false };
+                pos += line.len() + 2;
+
+                // Chunk extensions are allowed after the size, but we have no use for them
+                let size = line.split(|&b| b == b';').next().unwrap_or_default().trim_ascii();
+
+                if size.is_empty() || !size.iter().all(|b| b.is_ascii_hexdigit()) {
+                    return Err(Error::BadChunk);
+                }
+
+                let size = std::str::from_utf8(size).ok()
+                    .and_then(|size| usize::from_str_radix(size, 16).ok())
+                    .ok_or(Error::BadChunk)?;
+
+                if size > config.max_body_size.saturating_sub(body.len()) {
+                    return Err(Error::BodyTooLarge);
+                }
+
+                *chunk = match size {
+                    0 => Chunk::Trailers,
+                    _ => Chunk::Data(size)
+                };
+            },
+
+            Chunk::Data(remaining) => {
+                let available = (*remaining).min(buffer.len() - pos);
+
+                body.extend_from_slice(&buffer[pos..pos + available]);
+                pos += available;
+                *remaining -= available;
+
+                match *remaining {
+                    0 => *chunk = Chunk::DataEnd,
+                    _ => break false
+                }
+            },
+
+            Chunk::DataEnd => {
+                match buffer.get(pos..pos + 2) {
+                    Some(b"\r\n") => {
+                        pos += 2;
+                        *chunk = Chunk::Size;
+                    },
+                    Some(_) => return Err(Error::BadChunk),
+                    None => break false
+                }
+            },
+
+            Chunk::Trailers => {
+                let Some(line) = next_line(&buffer[pos..], config.max_head_size)? else { break false };
+                pos += line.len() + 2;
+
+                if line.is_empty() {
+                    break true;
+                }
+
+                // Trailer fields are kept alongside the regular headers
+                let (name, value) = parse_trailer(line).ok_or(Error::BadChunk)?;
+                trailer_len += line.len();
+
+                if trailer_len > config.max_head_size {
+                    return Err(Error::HeadTooLarge);
+                }
+
+                head.headers.push(name, value);
+            }
+        }
+    };
+
+    buffer.drain(0..pos);
+    Ok(done)
+}
+
+
+// Returns the next CRLF-terminated line (without the CRLF), or `None` if it hasn't fully arrived
+fn next_line(buffer: &[u8], max_len: usize) -> Result<Option<&[u8]>, Error> {
+    match buffer.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end <= max_len => Ok(Some(&buffer[0..end])),
+        None if buffer.len() <= max_len => Ok(None),
+        _ => Err(Error::BadChunk)
+    }
+}
+
+
+// Split a "name: value" trailer line
+fn parse_trailer(line: &[u8]) -> Option<(String, Vec<u8>)> {
+    let colon = line.iter().position(|&b| b == b':')?;
+    let name = std::str::from_utf8(&line[0..colon]).ok()?;
+
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_graphic()) {
+        return None;
+    }
+
+    Some((name.to_string(), line[colon + 1..].trim_ascii().to_vec()))
+}
+
+
+// Parse a request head, returning it along with its length in bytes. Room for headers starts out
+// small and grows as needed, up to as many as could fit within the size limit.
+fn parse_head(buffer: &[u8], config: &Config) -> Result<Option<(Head, usize)>, Error> {
+    // The shortest header line is 3 bytes ("a:" and a line break)
+    let max_headers = config.max_head_size / 3 + 1;
+    let mut headers = vec![EMPTY_HEADER; MIN_HEADERS.min(max_headers)];
+
+    let (req, status) = loop {
+        let mut req = httparse::Request::new(&mut headers);
+
+        match req.parse(buffer) {
+            Ok(status) => break (req, status),
+
+            Err(httparse::Error::TooManyHeaders) if headers.len() < max_headers => {
+                let len = (headers.len() * 2).min(max_headers);
+                headers = vec![EMPTY_HEADER; len];
+            },
+
+            Err(httparse::Error::TooManyHeaders) => return Err(Error::HeadTooLarge),
+            Err(e) => return Err(Error::Malformed(e))
+        }
+    };
+
+    match status {
+        httparse::Status::Complete(len) if len <= config.max_head_size => {
+            let head = Head {
+                method: Method::from(req.method.unwrap_or_default()),
+                target: req.path.unwrap_or_default().to_string(),
+                version: req.version.unwrap_or(1),
+                headers: Headers(req.headers.iter()
+                    .map(|header| (header.name.to_string(), header.value.to_vec()))
+                    .collect())
+            };
+
+            Ok(Some((head, len)))
+        },
+
+        // Wait for the rest of the head, as long as it stays within the size limit
+        httparse::Status::Partial if buffer.len() <= config.max_head_size => Ok(None),
+
+        _ => Err(Error::HeadTooLarge)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::{Error, Head, Method, Parser, Request};
+    use crate::{config::Config, response::Status};
+
+
+    // Feed `data` to a parser in pieces of `step` bytes, collecting every request it completes
+    fn parse_in_steps(data: &[u8], step: usize, config: &Config) -> Result<Vec<(Head, Vec<u8>)>, Error> {
+        let mut parser = Parser::default();
+        let mut buffer = vec![];
+        let mut requests = vec![];
+
+        for piece in data.chunks(step) {
+            buffer.extend_from_slice(piece);
+
+            while let Some(request) = parser.parse(&mut buffer, config)? {
+                requests.push(request);
+            }
+        }
+
+        assert!(buffer.is_empty(), "Left over: {:?}", String::from_utf8_lossy(&buffer));
+        Ok(requests)
+    }
+
+    // The request in `head`, which has to be complete
+    fn request(head: &str) -> Request {
+        let (head, body) = parse_in_steps(head.as_bytes(), head.len(), &Config::default()).unwrap().pop().unwrap();
+        Request::new(head, body, SocketAddr::from((Ipv4Addr::LOCALHOST, 1234)), 1)
+    }
+
+
+    #[test]
+    fn parses_a_head_arriving_byte_by_byte() {
+        let data = b"GET /index.html?v=2 HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n";
+        let requests = parse_in_steps(data, 1, &Config::default()).unwrap();
+
+        assert_eq!(requests.len(), 1);
+
+        let (head, body) = &requests[0];
+        assert_eq!(head.method, Method::Get);
+        assert_eq!(head.target, "/index.html?v=2");
+        assert_eq!(head.version, 1);
+        assert_eq!(head.headers.get("host"), Some(&b"example.com"[..]));
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn rejects_heads_over_the_size_limit() {
+        let config = Config { max_head_size: 64, ..Config::default() };
+        let mut data = b"GET / HTTP/1.1\r\nX-Padding: ".to_vec();
+        data.extend(vec![b'a'; 64]);
+
+        // Still incomplete, but already too large to ever fit
+        let error = parse_in_steps(&data, 16, &config).unwrap_err();
+        assert!(matches!(error, Error::HeadTooLarge));
+        assert_eq!(error.status(), Status::RequestHeaderFieldsTooLarge);
+
+        data.extend(b"\r\n\r\n");
+        assert!(matches!(parse_in_steps(&data, data.len(), &config), Err(Error::HeadTooLarge)));
+    }
+
+    #[test]
+    fn parses_heads_with_many_headers() {
+        let mut data = b"GET / HTTP/1.1\r\n".to_vec();
+
+        for i in 0..100 {
+            data.extend(format!("X-{}: {}\r\n", i, i).bytes());
+        }
+
+        data.extend(b"\r\n");
+
+        let requests = parse_in_steps(&data, data.len(), &Config::default()).unwrap();
+        assert_eq!(requests[0].0.headers.len(), 100);
+        assert_eq!(requests[0].0.headers.get("x-99"), Some(&b"99"[..]));
+
+        // The size limit still applies, however short the headers are
+        let config = Config { max_head_size: 256, ..Config::default() };
+        assert!(matches!(parse_in_steps(&data, data.len(), &config), Err(Error::HeadTooLarge)));
+
+        let mut data = b"GET / HTTP/1.1\r\n".to_vec();
+        data.extend(b"a:\n".repeat(100));
+        assert!(matches!(parse_in_steps(&data, 7, &config), Err(Error::HeadTooLarge)));
+    }
+
+    #[test]
+    fn parses_a_split_body_followed_by_a_pipelined_request() {
+        let data = b"POST /upload HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello worldGET /next HTTP/1.1\r\n\r\n";
+
+        for step in [1, 3, 50, data.len()] {
+            let requests = parse_in_steps(data, step, &Config::default()).unwrap();
+
+            assert_eq!(requests.len(), 2, "Step {}", step);
+            assert_eq!(requests[0].0.method, Method::Post);
+            assert_eq!(requests[0].1, b"hello world");
+            assert_eq!(requests[1].0.target, "/next");
+            assert!(requests[1].1.is_empty());
+        }
+    }
+
+    #[test]
+    fn tracks_body_progress() {
+        let config = Config::default();
+        let mut parser = Parser::default();
+        let mut buffer = b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n12345".to_vec();
+
+        assert!(parser.parse(&mut buffer, &config).unwrap().is_none());
+        assert_eq!(parser.body_received(), Some(5));
+
+        buffer.extend(b"67890");
+        assert!(parser.parse(&mut buffer, &config).unwrap().is_some());
+        assert_eq!(parser.body_received(), None);
+    }
+
+    #[test]
+    fn decodes_chunked_bodies_with_extensions_and_trailers() {
+        let data = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;name=value\r\nhello\r\n\
+            6 ; another\r\n world\r\n\
+            0\r\nX-Checksum: abc\r\n\r\n\
+            GET /next HTTP/1.1\r\n\r\n";
+
+        // Every split point, including in the middle of chunk sizes and CRLFs
+        for step in [1, 2, 7, data.len()] {
+            let requests = parse_in_steps(data, step, &Config::default()).unwrap();
+
+            assert_eq!(requests.len(), 2, "Step {}", step);
+            assert_eq!(requests[0].1, b"hello world");
+            assert_eq!(requests[0].0.headers.get("X-Checksum"), Some(&b"abc"[..]));
+            assert_eq!(requests[1].0.target, "/next");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        let config = Config::default();
+
+        for chunks in [&b"g\r\n"[..], b"\r\n", b"5\r\nhelloXX", b"0\r\nno colon\r\n\r\n"] {
+            let mut data = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+            data.extend_from_slice(chunks);
+
+            let error = Parser::default().parse(&mut data, &config).unwrap_err();
+            assert!(matches!(error, Error::BadChunk), "{:?}: {:?}", String::from_utf8_lossy(chunks), error);
+            assert_eq!(error.status(), Status::BadRequest);
+        }
+    }
+
+    #[test]
+    fn rejects_chunks_over_the_body_limit() {
+        let config = Config { max_body_size: 10, ..Config::default() };
+
+        // The second chunk takes the body past the limit before any of its data has arrived
+        let mut data = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n8\r\n12345678\r\n3\r\n".to_vec();
+        let error = Parser::default().parse(&mut data, &config).unwrap_err();
+
+        assert!(matches!(error, Error::BodyTooLarge));
+        assert_eq!(error.status(), Status::PayloadTooLarge);
+
+        // A size that doesn't even fit in a usize is just broken
+        let mut data = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffffffff\r\n".to_vec();
+        assert!(matches!(Parser::default().parse(&mut data, &config), Err(Error::BadChunk)));
+    }
+
+    #[test]
+    fn rejects_content_length_with_transfer_encoding() {
+        let mut data = b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n".to_vec();
+        let error = Parser::default().parse(&mut data, &Config::default()).unwrap_err();
+
+        assert!(matches!(error, Error::ConflictingLength));
+        assert_eq!(error.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn only_accepts_plain_chunked_encoding() {
+        for encoding in ["gzip", "gzip, chunked", "chunked, gzip", "chunked\r\nTransfer-Encoding: chunked", "identity"] {
+            let mut data = format!("POST / HTTP/1.1\r\nTransfer-Encoding: {}\r\n\r\n", encoding).into_bytes();
+            let error = Parser::default().parse(&mut data, &Config::default()).unwrap_err();
+
+            assert!(matches!(error, Error::UnsupportedEncoding), "{:?}: {:?}", encoding, error);
+            assert_eq!(error.status(), Status::NotImplemented);
+        }
+
+        let mut data = b"POST / HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\n0\r\n\r\n".to_vec();
+        assert!(Parser::default().parse(&mut data, &Config::default()).unwrap().is_some());
+    }
+
+    #[test]
+    fn checks_repeated_content_lengths() {
+        let parse = |headers: &str| {
+            let mut data = format!("POST / HTTP/1.1\r\n{}\r\n\r\nhello", headers).into_bytes();
+            Parser::default().parse(&mut data, &Config::default())
+        };
+
+        // Repeats are fine as long as they agree
+        let (_, body) = parse("Content-Length: 5\r\nContent-Length: 5").unwrap().unwrap();
+        assert_eq!(body, b"hello");
+
+        for headers in ["Content-Length: 5\r\nContent-Length: 4", "Content-Length: 5, 5", "Content-Length: +5", "Content-Length: "] {
+            let error = parse(headers).unwrap_err();
+
+            assert!(matches!(error, Error::BadContentLength), "{:?}: {:?}", headers, error);
+            assert_eq!(error.status(), Status::BadRequest);
+        }
+    }
+
+    #[test]
+    fn decodes_query_parameters() {
+        let request = request("GET /search?q=two+words&path=%2Fa%20b&flag&&q2=%E2%9C%93&x=1&x=2 HTTP/1.1\r\n\r\n");
+        let query = |key: &str| request.query.get(key).map(String::as_str);
+
+        assert_eq!(request.path, "/search");
+        assert_eq!(query("q"), Some("two words"));
+        assert_eq!(query("path"), Some("/a b"));
+        assert_eq!(query("flag"), Some(""));
+        assert_eq!(query("q2"), Some("\u{2713}"));
+        assert_eq!(query("x"), Some("2"));
+        assert_eq!(request.query.len(), 5);
+    }
+
+    #[test]
+    fn looks_up_headers_case_insensitively() {
+        let request = request("GET / HTTP/1.1\r\nX-Thing: one\r\nconnection: Keep-Alive, Upgrade\r\nx-thing: two\r\n\r\n");
+        let headers = &request.headers;
+
+        assert_eq!(headers.get_str("X-THING"), Some("one"));
+        assert_eq!(headers.get_all("x-thing").collect::<Vec<_>>(), [&b"one"[..], &b"two"[..]]);
+        assert!(headers.contains("Connection"));
+        assert!(!headers.contains("Content-Length"));
+
+        assert!(headers.has_token("Connection", "upgrade"));
+        assert!(headers.has_token("CONNECTION", "keep-alive"));
+        assert!(!headers.has_token("Connection", "close"));
+        assert!(!headers.has_token("Connection", "keep"));
+    }
+
+    #[test]
+    fn drops_the_scheme_and_authority_of_absolute_targets() {
+        let request = request("GET http://example.com:8080/some%20dir/file?q=1 HTTP/1.1\r\n\r\n");
+
+        assert_eq!(request.raw_path(), "/some%20dir/file");
+        assert_eq!(request.raw_query(), Some("q=1"));
+        assert_eq!(request.path, "/some dir/file");
+        assert_eq!(request.query.get("q").map(String::as_str), Some("1"));
+
+        let bare = self::request("GET https://example.com HTTP/1.1\r\n\r\n");
+        assert_eq!(bare.path, "/");
+        assert_eq!(bare.raw_query(), None);
+    }
+
+    #[test]
+    fn treats_methods_case_sensitively() {
+        assert_eq!(Method::from("GET"), Method::Get);
+        assert_eq!(Method::from("OPTIONS"), Method::Options);
+        assert_eq!(Method::from("get"), Method::Other("get".to_string()));
+        assert_eq!(Method::from("Head"), Method::Other("Head".to_string()));
+
+        assert_eq!(Method::from("get").as_str(), "get");
+        assert_eq!(request("get / HTTP/1.1\r\n\r\n").method, Method::Other("get".to_string()));
+    }
+}
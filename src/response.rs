@@ -1,16 +1,24 @@
 // Build & send HTTP Responses
 
-use std::{io::Write, path::Path};
+use std::{fs::File, io::{self, Read, Seek, Write}, path::Path, time::Duration};
 
 
 // pub type Result = std::result::Result<Response, HttpError>;
 
 
+// Methods listed in the Allow header of OPTIONS and 405 responses, unless a handler sets its own
+pub const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+
 #[allow(dead_code)]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Status {
+    // 1xx
+    SwitchingProtocols,
+
     // 2xx
     Ok,
+    NoContent,
 
     // 3xx
     MovedPermanently,
@@ -25,17 +33,24 @@ pub enum Status {
     Forbidden,
     NotFound,
     MethodNotAllowed,
+    RequestTimeout,
+    PayloadTooLarge,
     TooManyRequests,
+    RequestHeaderFieldsTooLarge,
 
     // 5xx
     InternalServerError,
+    NotImplemented,
     ServiceUnavailable
 }
 
 impl Status {
     pub fn as_str(&self) -> &'static str {
         match *self {
+            Status::SwitchingProtocols => "101 Switching Protocols",
+
             Status::Ok => "200 Ok",
+            Status::NoContent => "204 No Content",
 
             Status::MovedPermanently => "301 Moved Permanently",
             Status::Found => "302 Found",
@@ -48,12 +63,21 @@ impl Status {
             Status::Forbidden => "403 Forbidden",
             Status::NotFound => "404 Not Found",
             Status::MethodNotAllowed => "405 Method Not Allowed",
+            Status::RequestTimeout => "408 Request Timeout",
+            Status::PayloadTooLarge => "413 Payload Too Large",
             Status::TooManyRequests => "429 Too Many Requests",
+            Status::RequestHeaderFieldsTooLarge => "431 Request Header Fields Too Large",
 
             Status::InternalServerError => "500 Internal Server Error",
+            Status::NotImplemented => "501 Not Implemented",
             Status::ServiceUnavailable => "503 Service Unavailable"
         }
     }
+
+    // 1xx and 204 responses never have a body
+    pub fn has_body(&self) -> bool {
+        !matches!(self, Status::SwitchingProtocols | Status::NoContent)
+    }
 }
 
 
@@ -106,53 +130,197 @@ pub fn mime_from_path(path: &Path) -> Option<&'static str> {
 pub struct Header(&'static str, Vec<u8>);
 
 
+// How many bytes of a streamed body are read at once
+const BLOCK_SIZE: usize = 16 * 1024;
+
+
+// The body of a Response, either held in memory or pulled from a source while it's being sent
+#[allow(dead_code)]
+pub enum Body {
+    Bytes(Vec<u8>),
+    File(File, u64), // File & the number of bytes left to send from it
+    Reader(Box<dyn Read + Send>), // Unknown length, sent chunked
+    Chunks(Box<dyn Iterator<Item = Vec<u8>> + Send>), // Unknown length, sent chunked
+    Events(String) // An event stream on a topic, written by the server as events are sent to it
+}
+
+impl Body {
+    // Send the rest of a file, starting from its current position
+    pub fn file(mut file: File) -> io::Result<Self> {
+        let metadata = file.metadata()?;
+
+        if !metadata.is_file() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a regular file"));
+        }
+
+        let len = metadata.len();
+        let pos = file.stream_position()?;
+
+        Ok(Body::File(file, len.saturating_sub(pos)))
+    }
+
+    #[allow(dead_code)]
+    pub fn reader<R: Read + Send + 'static>(reader: R) -> Self {
+        Body::Reader(Box::new(reader))
+    }
+
+    #[allow(dead_code)]
+    pub fn chunks<I: IntoIterator<Item = Vec<u8>>>(chunks: I) -> Self
+        where I::IntoIter: Send + 'static
+    {
+        Body::Chunks(Box::new(chunks.into_iter()))
+    }
+
+    // The length of the body, if it's known up front. There's no `is_empty`, a body of unknown
+    // length can't tell.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::File(_, len) => Some(*len),
+            Body::Reader(_) | Body::Chunks(_) | Body::Events(_) => None
+        }
+    }
+
+    // Pull the next block of the body, `None` once it's been exhausted
+    fn next_block(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self {
+            Body::Bytes(bytes) if bytes.is_empty() => Ok(None),
+            Body::Bytes(bytes) => Ok(Some(std::mem::take(bytes))),
+
+            Body::File(_, 0) => Ok(None),
+            Body::File(file, remaining) => {
+                let mut block = vec![0; BLOCK_SIZE.min(*remaining as usize)];
+                let n = file.read(&mut block)?;
+
+                if n == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File shorter than its announced length"));
+                }
+
+                block.truncate(n);
+                *remaining -= n as u64;
+                Ok(Some(block))
+            },
+
+            Body::Reader(reader) => {
+                let mut block = vec![0; BLOCK_SIZE];
+                let n = reader.read(&mut block)?;
+
+                block.truncate(n);
+                Ok(Some(block).filter(|block| !block.is_empty()))
+            },
+
+            // Skip empty items, an empty chunk would end the body early
+            Body::Chunks(chunks) => Ok(chunks.find(|chunk| !chunk.is_empty())),
+
+            Body::Events(_) => Ok(None)
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(value: Vec<u8>) -> Self {
+        Body::Bytes(value)
+    }
+}
+
+impl std::fmt::Debug for Body {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Body::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            Body::File(file, len) => write!(f, "File({:?}, {} bytes)", file, len),
+            Body::Reader(_) => f.write_str("Reader"),
+            Body::Chunks(_) => f.write_str("Chunks"),
+            Body::Events(topic) => write!(f, "Events({:?})", topic)
+        }
+    }
+}
+
+
 // A finalized HTTP Response, ready to be sent
 #[derive(Debug)]
 pub struct Response {
     pub status: Status,
     pub headers: Vec<Header>,
-    pub body: Vec<u8>
+    pub body: Body
 }
 
 impl Response {
     // `true` if a header is found, `false` otherwise
     fn contains_header(&self, name: &'static str) -> bool {
-        let name = name.to_lowercase();
-        self.headers.iter().any(|header| header.0.to_lowercase() == name)
+        self.headers.iter().any(|header| header.0.eq_ignore_ascii_case(name))
     }
 
     // Add basic headers that all responses should have
-    fn add_basic_headers(&mut self) {
-        if !self.contains_header("Content-Length") {
-            self.headers.push(Header("Content-Length", self.body.len().to_string().into_bytes()));
+    fn add_basic_headers(&mut self, chunked: bool) {
+        if self.status == Status::MethodNotAllowed && !self.contains_header("Allow") {
+            self.headers.push(Header("Allow", ALLOWED_METHODS.as_bytes().to_vec()));
+        }
+
+        // Responses that can't have a body don't get any framing headers either
+        if !self.status.has_body() || self.contains_header("Content-Length") || self.contains_header("Transfer-Encoding") {
+            return;
+        }
+
+        match self.body.len() {
+            Some(len) => self.headers.push(Header("Content-Length", len.to_string().into_bytes())),
+            None if chunked => self.headers.push(Header("Transfer-Encoding", b"chunked".to_vec())),
+            None => ()
+        }
+    }
+
+    // `true` if the handler asked for the connection to be closed after this response, "close" can
+    // be any of the comma-separated Connection options
+    pub fn closes_connection(&self) -> bool {
+        self.headers.iter()
+            .filter(|header| header.0.eq_ignore_ascii_case("Connection"))
+            .flat_map(|header| header.1.split(|&b| b == b','))
+            .any(|option| option.trim_ascii().eq_ignore_ascii_case(b"close"))
+    }
+
+    // Set the Connection (& Keep-Alive) headers, `keep_alive` holds the idle timeout and the number
+    // of requests left on the connection
+    pub fn set_keep_alive(&mut self, keep_alive: Option<(Duration, usize)>) {
+        self.headers.retain(|header| !header.0.eq_ignore_ascii_case("Connection") && !header.0.eq_ignore_ascii_case("Keep-Alive"));
+
+        match keep_alive {
+            Some((timeout, max)) => {
+                self.headers.push(Header("Connection", b"keep-alive".to_vec()));
+                self.headers.push(Header("Keep-Alive", format!("timeout={}, max={}", timeout.as_secs(), max).into_bytes()));
+            },
+
+            None => self.headers.push(Header("Connection", b"close".to_vec()))
         }
     }
 
-    // Calculate how many bytes this response will take up
-    fn calculate_size(&self) -> usize {
-        let status_len = self.status.as_str().as_bytes().len();
+    // Calculate how many bytes the status line & headers will take up
+    fn calculate_head_size(&self) -> usize {
+        let status_len = self.status.as_str().len();
         let mut header_len = 0;
 
         for header in &self.headers {
-            header_len += header.0.as_bytes().len() + header.1.len();
+            header_len += header.0.len() + header.1.len() + 4; // +4 for ": " and "\r\n"
         }
 
-        status_len + header_len + self.body.len() + 15 // +15 for "HTTP/1.1 " + the 3 newlines (\r\n)
+        status_len + header_len + 13 // +13 for "HTTP/1.1 " + the last 2 newlines (\r\n)
     }
 
-    // Combine all headers into a Vec of bytes
-    fn collect_headers(&self) -> std::io::Result<Vec<u8>> {
-        let mut headers = vec![];
+    // The status line & headers
+    fn head_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut head = Vec::with_capacity(self.calculate_head_size());
+
+        head.write_all(b"HTTP/1.1 ")?;
+        head.write_all(self.status.as_str().as_bytes())?;
 
         for header in &self.headers {
-            headers.write(b"\r\n")?;
-            headers.write(header.0.as_bytes())?;
-            headers.write(b": ")?;
-            headers.write(&header.1)?;
+            head.write_all(b"\r\n")?;
+            head.write_all(header.0.as_bytes())?;
+            head.write_all(b": ")?;
+            head.write_all(&header.1)?;
         }
 
-        headers.flush()?;
-        Ok(headers)
+        head.write_all(b"\r\n\r\n")?;
+        Ok(head)
     }
 
     // Create a simple response with a status code and text for the body
@@ -160,22 +328,90 @@ impl Response {
         Response {
             status,
             headers: vec![],
-            body: body.into()
+            body: Body::Bytes(body.into())
         }
     }
 
-    pub fn try_into_bytes(mut self) -> std::io::Result<Vec<u8>> {
-        self.add_basic_headers();
+    // Start a Server-Sent Events stream, which is sent every event published to `topic` (see
+    // `Publisher::send_event`) until the client disconnects
+    pub fn event_stream(topic: &str) -> Self {
+        Builder::with_status(Status::Ok)
+            .add_header("Content-Type", "text/event-stream")
+            .add_header("Cache-Control", "no-cache")
+            .set_body(Body::Events(topic.to_string()))
+            .build()
+    }
 
-        let mut res = Vec::with_capacity(self.calculate_size());
+    // `true` if this response starts an event stream
+    pub fn is_event_stream(&self) -> bool {
+        matches!(self.body, Body::Events(_))
+    }
 
-        res.write(b"HTTP/1.1 ")?;
-        res.write(self.status.as_str().as_bytes())?;
-        res.write(&self.collect_headers()?)?;
-        res.write(b"\r\n\r\n")?;
-        res.write(&self.body)?;
+    // Turn the response into a sequence of byte blocks ready to be written to a socket. HTTP/1.0
+    // clients (`version` 0) don't understand chunked encoding, so bodies of unknown length are sent
+    // as-is and the connection has to be closed afterwards. Responses to HEAD requests keep all of
+    // their headers but leave out the body (`with_body`). Event streams only get their head, the
+    // events themselves are written as they're sent.
+    pub fn encode(mut self, version: u8, with_body: bool) -> Encoder {
+        let chunked = self.body.len().is_none() && version > 0;
+        self.add_basic_headers(chunked);
+
+        let head = self.head_bytes();
+        let finished = !with_body || !self.status.has_body() || self.is_event_stream();
+
+        Encoder {
+            head: Some(head),
+            body: self.body,
+            chunked,
+            finished
+        }
+    }
+}
 
-        Ok(res)
+
+// Yields a Response's head, then its body one block at a time (with chunked framing if the body
+// has no known length)
+#[derive(Debug)]
+pub struct Encoder {
+    head: Option<io::Result<Vec<u8>>>,
+    body: Body,
+    chunked: bool,
+    finished: bool
+}
+
+impl Iterator for Encoder {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(head) = self.head.take() {
+            return Some(head);
+        }
+
+        if self.finished {
+            return None;
+        }
+
+        match self.body.next_block() {
+            Ok(Some(block)) if self.chunked => {
+                let mut framed = Vec::with_capacity(block.len() + 12);
+
+                framed.extend_from_slice(format!("{:x}\r\n", block.len()).as_bytes());
+                framed.extend_from_slice(&block);
+                framed.extend_from_slice(b"\r\n");
+                Some(Ok(framed))
+            },
+            Ok(Some(block)) => Some(Ok(block)),
+
+            Ok(None) => {
+                self.finished = true;
+                Some(Ok(b"0\r\n\r\n".to_vec())).filter(|_| self.chunked)
+            },
+
+            Err(e) => {
+                self.finished = true;
+                Some(Err(e))
+            }
+        }
     }
 }
 
@@ -185,7 +421,7 @@ impl Response {
 pub struct Builder {
     status: Status,
     headers: Vec<Header>,
-    body: Vec<u8>
+    body: Body
 }
 
 impl Builder {
@@ -193,7 +429,7 @@ impl Builder {
         Builder {
             status,
             headers: vec![],
-            body: vec![]
+            body: Body::Bytes(vec![])
         }
     }
 
@@ -202,8 +438,8 @@ impl Builder {
         self
     }
 
-    pub fn set_body(mut self, body: Vec<u8>) -> Self {
-        self.body = body;
+    pub fn set_body<B: Into<Body>>(mut self, body: B) -> Self {
+        self.body = body.into();
         self
     }
 
@@ -215,3 +451,91 @@ impl Builder {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::{self, File}, io::{self, Write}};
+
+    use super::{Body, Builder, Encoder, Response, Status};
+
+
+    // Everything an encoder yields, joined, or the first error
+    fn collect(encoder: Encoder) -> io::Result<String> {
+        let blocks = encoder.collect::<io::Result<Vec<_>>>()?;
+        Ok(String::from_utf8(blocks.concat()).unwrap())
+    }
+
+    fn ok(body: Body) -> Response {
+        Builder::with_status(Status::Ok).set_body(body).build()
+    }
+
+    // A file under the system temp dir holding `contents`, opened for reading and already unlinked
+    fn file(name: &str, contents: &[u8]) -> File {
+        let path = std::env::temp_dir().join(format!("ws2-{}-{}", name, std::process::id()));
+
+        File::create(&path).unwrap().write_all(contents).unwrap();
+        let file = File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        file
+    }
+
+
+    #[test]
+    fn finds_close_among_connection_options() {
+        let closes = |values: &[&'static str]| {
+            values.iter()
+                .fold(Builder::with_status(Status::Ok), |builder, value| builder.add_header("Connection", *value))
+                .build()
+                .closes_connection()
+        };
+
+        assert!(closes(&["close"]));
+        assert!(closes(&["keep-alive, close"]));
+        assert!(closes(&[" Close ,upgrade"]));
+        assert!(closes(&["keep-alive", "close"]));
+
+        assert!(!closes(&[]));
+        assert!(!closes(&["keep-alive"]));
+        assert!(!closes(&["closed, x-close"]));
+    }
+
+    #[test]
+    fn frames_bodies_of_unknown_length_as_chunks() {
+        let chunks = collect(ok(Body::chunks([b"abc".to_vec(), vec![], b"0123456789".to_vec(), vec![]])).encode(1, true)).unwrap();
+        let reader = collect(ok(Body::reader(&b"hello"[..])).encode(1, true)).unwrap();
+
+        assert_eq!(chunks, "HTTP/1.1 200 Ok\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\na\r\n0123456789\r\n0\r\n\r\n");
+        assert_eq!(reader, "HTTP/1.1 200 Ok\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n");
+
+        let empty = collect(ok(Body::chunks([vec![], vec![]])).encode(1, true)).unwrap();
+        assert!(empty.ends_with("chunked\r\n\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn sends_files_with_their_length() {
+        let response = ok(Body::file(file("response-file", b"file contents")).unwrap());
+        assert_eq!(collect(response.encode(1, true)).unwrap(), "HTTP/1.1 200 Ok\r\nContent-Length: 13\r\n\r\nfile contents");
+
+        // The file shrank after its length was taken
+        let response = ok(Body::File(file("response-short", b"short"), 10));
+        let error = collect(response.encode(1, true)).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn sends_bodies_of_unknown_length_unframed_to_http_1_0() {
+        let body = collect(ok(Body::chunks([b"abc".to_vec(), b"def".to_vec()])).encode(0, true)).unwrap();
+        assert_eq!(body, "HTTP/1.1 200 Ok\r\n\r\nabcdef");
+    }
+
+    #[test]
+    fn leaves_out_the_body_without_with_body() {
+        let bytes = collect(ok(b"hello".to_vec().into()).encode(1, false)).unwrap();
+        let chunked = collect(ok(Body::reader(&b"hello"[..])).encode(1, false)).unwrap();
+
+        assert_eq!(bytes, "HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\n");
+        assert_eq!(chunked, "HTTP/1.1 200 Ok\r\nTransfer-Encoding: chunked\r\n\r\n");
+    }
+}
@@ -0,0 +1,240 @@
+// Server-Sent Events (text/event-stream) responses, which stay open for events sent later on
+
+use std::{collections::VecDeque, time::Duration};
+
+
+// A single event record
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>, // Event type, "message" if left out
+    data: String,
+    retry: Option<Duration> // Tells the client how long to wait before reconnecting
+}
+
+impl Event {
+    pub fn new<S: Into<String>>(data: S) -> Self {
+        Event {
+            id: None,
+            event: None,
+            data: data.into(),
+            retry: None
+        }
+    }
+
+    // Line breaks would start a new field and NULs make clients ignore the id, so both are dropped
+    pub fn with_id<S: Into<String>>(mut self, id: S) -> Self {
+        self.id = Some(id.into().replace(['\r', '\n', '\0'], ""));
+        self
+    }
+
+    pub fn with_event<S: Into<String>>(mut self, event: S) -> Self {
+        self.event = Some(event.into().replace(['\r', '\n'], ""));
+        self
+    }
+
+    pub fn with_retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    // The record as it's sent, multi-line data is split over several "data:" fields
+    pub fn encode(&self) -> Vec<u8> {
+        let mut record = String::new();
+
+        if let Some(id) = &self.id {
+            record.push_str(&format!("id: {}\n", id));
+        }
+
+        if let Some(event) = &self.event {
+            record.push_str(&format!("event: {}\n", event));
+        }
+
+        if let Some(retry) = self.retry {
+            record.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+
+        for line in self.data.split("\r\n").flat_map(|line| line.split(['\r', '\n'])) {
+            record.push_str(&format!("data: {}\n", line));
+        }
+
+        record.push('\n');
+        record.into_bytes()
+    }
+}
+
+
+// The most recent events sent to a topic, replayed to clients reconnecting with a Last-Event-ID
+#[derive(Debug)]
+pub struct History {
+    events: VecDeque<Event>,
+    capacity: usize
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History {
+            events: VecDeque::new(),
+            capacity
+        }
+    }
+
+    pub fn push(&mut self, event: Event) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+
+        self.events.push_back(event);
+    }
+
+    // Events sent after the one with `last_id`. If it's not in the history anymore the client has
+    // missed an unknown number of events, so it gets everything that's left.
+    pub fn since(&self, last_id: &str) -> impl Iterator<Item = &Event> {
+        let start = self.events.iter()
+            .rposition(|event| event.id() == Some(last_id))
+            .map_or(0, |i| i + 1);
+
+        self.events.range(start..)
+    }
+}
+
+
+// The event stream a client's connection has been turned into
+#[derive(Debug)]
+pub struct EventStream {
+    topic: String,
+    chunked: bool, // HTTP/1.1 streams are sent with chunked encoding, HTTP/1.0 ones as-is
+    last_event_id: Option<String>, // From the request, until the missed events have been replayed
+    output: Vec<u8> // Encoded records waiting to be written
+}
+
+impl EventStream {
+    pub fn new(topic: String, chunked: bool, last_event_id: Option<String>) -> Self {
+        EventStream {
+            topic,
+            chunked,
+            last_event_id,
+            output: vec![]
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    // Take the Last-Event-ID the client reconnected with, if any
+    pub fn take_last_event_id(&mut self) -> Option<String> {
+        self.last_event_id.take()
+    }
+
+    pub fn send(&mut self, event: &Event) {
+        self.write(&event.encode());
+    }
+
+    // Comments are ignored by clients, but keep idle connections from being closed along the way
+    pub fn comment(&mut self, text: &str) {
+        self.write(format!(": {}\n\n", text.replace(['\r', '\n'], " ")).as_bytes());
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        if self.chunked {
+            self.output.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+            self.output.extend_from_slice(data);
+            self.output.extend_from_slice(b"\r\n");
+        }
+        else {
+            self.output.extend_from_slice(data);
+        }
+    }
+
+    // Take the records waiting to be written
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Event, EventStream, History};
+
+
+    fn ids<'a>(events: impl Iterator<Item = &'a Event>) -> Vec<&'a str> {
+        events.map(|event| event.id().unwrap()).collect()
+    }
+
+
+    #[test]
+    fn encodes_records() {
+        assert_eq!(Event::new("hello").encode(), b"data: hello\n\n");
+
+        let event = Event::new("{}")
+            .with_id("7")
+            .with_event("update")
+            .with_retry(Duration::from_secs(3));
+
+        assert_eq!(event.encode(), b"id: 7\nevent: update\nretry: 3000\ndata: {}\n\n");
+    }
+
+    #[test]
+    fn splits_multi_line_data() {
+        // Every kind of line break starts a new data field, blank lines included
+        let event = Event::new("one\ntwo\r\nthree\rfour\n\nsix");
+        assert_eq!(event.encode(), b"data: one\ndata: two\ndata: three\ndata: four\ndata: \ndata: six\n\n");
+
+        assert_eq!(Event::new("").encode(), b"data: \n\n");
+    }
+
+    #[test]
+    fn keeps_line_breaks_out_of_fields() {
+        let event = Event::new("x").with_id("1\nid: 2\0").with_event("a\r\nevent: b");
+        assert_eq!(event.encode(), b"id: 1id: 2\nevent: aevent: b\ndata: x\n\n");
+    }
+
+    #[test]
+    fn evicts_the_oldest_events() {
+        let mut history = History::new(3);
+
+        for id in 1..=5 {
+            history.push(Event::new("x").with_id(id.to_string()));
+        }
+
+        assert_eq!(ids(history.since("3")), ["4", "5"]);
+        assert_eq!(ids(history.since("5")), Vec::<&str>::new());
+
+        // Too far back (or never sent), everything that's left is replayed
+        assert_eq!(ids(history.since("1")), ["3", "4", "5"]);
+        assert_eq!(ids(history.since("unknown")), ["3", "4", "5"]);
+
+        let mut disabled = History::new(0);
+        disabled.push(Event::new("x").with_id("1"));
+        assert_eq!(disabled.since("0").count(), 0);
+    }
+
+    #[test]
+    fn frames_output_for_the_connection() {
+        let mut chunked = EventStream::new(String::from("news"), true, None);
+        chunked.send(&Event::new("hi"));
+        chunked.comment("keep\nalive");
+
+        assert_eq!(chunked.take_output(), b"a\r\ndata: hi\n\n\r\ne\r\n: keep alive\n\n\r\n");
+        assert!(chunked.take_output().is_empty());
+
+        let mut plain = EventStream::new(String::from("news"), false, Some(String::from("3")));
+        plain.send(&Event::new("hi"));
+
+        assert_eq!(plain.take_output(), b"data: hi\n\n");
+        assert_eq!(plain.take_last_event_id().as_deref(), Some("3"));
+        assert_eq!(plain.take_last_event_id(), None);
+    }
+}
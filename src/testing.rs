@@ -0,0 +1,137 @@
+// Helpers shared by tests that run a real server
+
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpStream},
+    sync::{mpsc, Arc},
+    thread
+};
+
+use crate::{config::Config, http::Server, logging::{LogLevel, Logger}, request::Request, response::{Response, Status}};
+
+
+// Bigger than a socket's send & receive buffers put together
+pub const LARGE_BODY: usize = 32 * 1024 * 1024;
+
+
+// A server on a free port of localhost, using `config`
+pub fn server(config: Config) -> Server {
+    Server::bind(Ipv4Addr::LOCALHOST.into(), 0, Logger::new(LogLevel::Error))
+        .and_then(|server| server.with_config(config))
+        .unwrap()
+}
+
+// Run `server` on its own thread, returning the address it listens on
+pub fn start<F>(server: Server, cb: F) -> SocketAddr
+    where F: Fn(Request, Arc<Config>, Logger) -> Response + Send + Sync + 'static
+{
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        sender.send(server.local_addr().unwrap()).unwrap();
+        server.listen(cb);
+    });
+
+    receiver.recv().unwrap()
+}
+
+// Run a server answering every request with its path (see `echo_path`)
+pub fn start_server(config: Config) -> SocketAddr {
+    start(server(config), echo_path)
+}
+
+// Answers with the request's path, or a large body for "/large"
+pub fn echo_path(request: Request, _: Arc<Config>, _: Logger) -> Response {
+    match request.path.as_str() {
+        "/large" => Response::text(Status::Ok, vec![b'x'; LARGE_BODY]),
+        path => Response::text(Status::Ok, path)
+    }
+}
+
+
+// Read a response head, up to & including the empty line
+pub fn read_head<R: Read>(stream: &mut R) -> String {
+    let mut data = vec![];
+    let mut byte = [0u8; 1];
+
+    while !data.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        data.push(byte[0]);
+    }
+
+    String::from_utf8(data).unwrap()
+}
+
+// Read a response with a Content-Length body, returning the head & body
+pub fn read_response<R: Read>(stream: &mut R) -> (String, String) {
+    let head = read_head(stream);
+    let length: usize = head.lines()
+        .find_map(|line| line.strip_prefix("Content-Length: "))
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).unwrap();
+
+    (head, String::from_utf8(body).unwrap())
+}
+
+
+// Open a WebSocket connection to `path`, without compression
+pub fn websocket(address: SocketAddr, path: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(address).unwrap();
+
+    write!(stream, "GET {} HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n", path).unwrap();
+
+    let head = read_head(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 101 "), "{}", head);
+
+    stream
+}
+
+// Send a single text message, masked like clients have to
+pub fn send_text(stream: &mut TcpStream, text: &str) {
+    assert!(text.len() <= 125);
+
+    let mask = [1, 2, 3, 4];
+    let mut frame = vec![0x81, 0x80 | text.len() as u8];
+
+    frame.extend_from_slice(&mask);
+    frame.extend(text.bytes().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    stream.write_all(&frame).unwrap();
+}
+
+// Read a single frame, returning its opcode & payload
+pub fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).unwrap();
+
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).unwrap();
+            u16::from_be_bytes(len) as usize
+        },
+        127 => {
+            let mut len = [0u8; 8];
+            stream.read_exact(&mut len).unwrap();
+            u64::from_be_bytes(len) as usize
+        },
+        len => len as usize
+    };
+
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).unwrap();
+
+    (head[0] & 0x0F, payload)
+}
+
+// Read a single frame, which has to be a text message
+pub fn read_text(stream: &mut TcpStream) -> String {
+    let (opcode, payload) = read_frame(stream);
+    assert_eq!(opcode, 0x1);
+
+    String::from_utf8(payload).unwrap()
+}
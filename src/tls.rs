@@ -0,0 +1,484 @@
+// TLS termination with rustls, only built with the "tls" feature
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime}
+};
+
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+    ServerConnection
+};
+
+use crate::config::Config;
+
+
+// Build the server side TLS configuration from the certificates in `config`, `None` if there
+// aren't any
+pub fn server_config(config: &Config) -> io::Result<Option<Arc<ServerConfig>>> {
+    let builder = ServerConfig::builder().with_no_client_auth();
+    let provider = builder.crypto_provider().clone();
+
+    let default = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(load_key(cert, key, &provider)?),
+        _ => None
+    };
+
+    let mut hosts = HashMap::new();
+
+    for (host, (cert, key)) in &config.tls_hosts {
+        hosts.insert(host.to_ascii_lowercase(), load_key(cert, key, &provider)?);
+    }
+
+    if default.is_none() && hosts.is_empty() {
+        return Ok(None);
+    }
+
+    let mut config = builder.with_cert_resolver(Arc::new(CertResolver { hosts, default }));
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(Some(Arc::new(config)))
+}
+
+
+// Load a PEM certificate chain (leaf first) & its private key
+fn load_key(cert_path: &Path, key_path: &Path, provider: &CryptoProvider) -> io::Result<Arc<CertifiedKey>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(cert_path, e))?;
+
+    if certs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("No certificates found in {}", cert_path.display())));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| pem_error(key_path, e))?;
+
+    match CertifiedKey::from_der(certs, key, provider) {
+        Ok(key) => Ok(Arc::new(key)),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", key_path.display(), e)))
+    }
+}
+
+
+fn pem_error(path: &Path, e: rustls::pki_types::pem::Error) -> io::Error {
+    match e {
+        rustls::pki_types::pem::Error::Io(e) => io::Error::new(e.kind(), format!("{}: {}", path.display(), e)),
+        e => io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+    }
+}
+
+
+// Picks a certificate by the server name the client asked for (SNI), falling back to the default
+// one for unknown names and clients that didn't send one
+#[derive(Debug)]
+struct CertResolver {
+    hosts: HashMap<String, Arc<CertifiedKey>>, // Lowercase hostnames, wildcards start with "*."
+    default: Option<Arc<CertifiedKey>>
+}
+
+impl CertResolver {
+    // Exact names win over wildcards, which only stand in for the leftmost label
+    // ("*.example.com" matches "www.example.com", but not "example.com" or "a.b.example.com")
+    fn find(&self, name: &str) -> Option<&Arc<CertifiedKey>> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+        if let Some(key) = self.hosts.get(&name) {
+            return Some(key);
+        }
+
+        let (_, parent) = name.split_once('.')?;
+        self.hosts.get(&format!("*.{}", parent))
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        client_hello.server_name()
+            .and_then(|name| self.find(name))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+
+// Watches the certificate & key files in a config, so they can be reloaded once they change
+#[derive(Debug)]
+pub struct Reloader {
+    files: Vec<(PathBuf, Option<(SystemTime, u64)>)>, // Modification time & size when last checked
+    interval: Duration,
+    next_check: Instant
+}
+
+impl Reloader {
+    // `None` if there's nothing to watch, or reloading is turned off
+    pub fn new(config: &Config) -> Option<Self> {
+        let mut files: Vec<PathBuf> = config.tls_cert.iter().chain(&config.tls_key).cloned().collect();
+
+        for (cert, key) in config.tls_hosts.values() {
+            files.push(cert.clone());
+            files.push(key.clone());
+        }
+
+        if files.is_empty() || config.tls_reload_interval.is_zero() {
+            return None;
+        }
+
+        Some(Reloader {
+            files: files.into_iter().map(|path| { let stamp = stamp(&path); (path, stamp) }).collect(),
+            interval: config.tls_reload_interval,
+            next_check: Instant::now() + config.tls_reload_interval
+        })
+    }
+
+    // Time left until the files should be checked again
+    pub fn timeout(&self) -> Duration {
+        self.next_check.saturating_duration_since(Instant::now())
+    }
+
+    // `true` if any of the files changed since they were last checked, which only happens once
+    // per interval
+    pub fn changed(&mut self) -> bool {
+        let now = Instant::now();
+
+        if now < self.next_check {
+            return false;
+        }
+
+        self.next_check = now + self.interval;
+
+        let mut changed = false;
+
+        for (path, last) in &mut self.files {
+            let current = stamp(path);
+            changed |= current != *last;
+            *last = current;
+        }
+
+        changed
+    }
+}
+
+
+// When a file was last modified & how big it is, `None` if it can't be read
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+
+// Read decrypted data into `buf`. At most one read is done on the socket, so a blocking socket is
+// only read from when the poller said it's readable. Returns `WouldBlock` if the records read so far
+// didn't hold any data (during the handshake, for example) and 0 once the peer has gone away.
+pub fn read(tls: &mut ServerConnection, stream: &mut TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+    match tls.reader().read(buf) {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+        result => return result
+    }
+
+    if tls.read_tls(stream)? == 0 {
+        return Ok(0);
+    }
+
+    let processed = tls.process_new_packets();
+
+    // Handshake messages, or the alert explaining why processing failed
+    match flush(tls, stream) {
+        Err(e) if e.kind() != io::ErrorKind::WouldBlock => return Err(e),
+        _ => ()
+    }
+
+    processed.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    tls.reader().read(buf)
+}
+
+
+// Move any decrypted data rustls is still holding on to into `buffer`, the socket won't be
+// readable again for it
+pub fn read_buffered(tls: &mut ServerConnection, buffer: &mut Vec<u8>) -> io::Result<()> {
+    let mut chunk = [0u8; 2048];
+
+    loop {
+        match tls.reader().read(&mut chunk) {
+            Ok(0) => return Ok(()),
+            Ok(n) => buffer.extend_from_slice(&chunk[0..n]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+
+// Encrypt as much of `data` as rustls will take and send it along. Records the socket can't take
+// yet stay with rustls until the next `flush`.
+pub fn write(tls: &mut ServerConnection, stream: &mut TcpStream, data: &[u8]) -> io::Result<usize> {
+    // Don't pile up more records while older ones are stuck
+    flush(tls, stream)?;
+
+    let n = tls.writer().write(data)?;
+
+    match flush(tls, stream) {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(n),
+        result => result.map(|_| n)
+    }
+}
+
+
+// Write out every encrypted record that's waiting, fails with `WouldBlock` if the socket can't
+// take them all
+pub fn flush(tls: &mut ServerConnection, stream: &mut TcpStream) -> io::Result<()> {
+    while tls.wants_write() {
+        tls.write_tls(stream)?;
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        fs,
+        io::{self, Read, Write},
+        net::{SocketAddr, TcpStream},
+        path::PathBuf,
+        sync::Arc,
+        thread,
+        time::Duration
+    };
+
+    use rustls::{pki_types::{CertificateDer, ServerName}, ClientConfig, ClientConnection, RootCertStore, ServerConfig, StreamOwned};
+
+    use super::{load_key, server_config, CertResolver};
+    use crate::{config::Config, testing::{read_response, start_server}};
+
+
+    // A self-signed certificate for `hosts`, written out as PEM files that are removed on drop
+    struct TestCert {
+        dir: PathBuf,
+        cert: CertificateDer<'static>
+    }
+
+    impl TestCert {
+        fn new(name: &str, hosts: &[&str]) -> Self {
+            let dir = std::env::temp_dir().join(format!("ws2-tls-{}-{}", name, std::process::id()));
+            let hosts = hosts.iter().map(|host| host.to_string()).collect::<Vec<_>>();
+            let generated = rcgen::generate_simple_self_signed(hosts).unwrap();
+
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("cert.pem"), generated.cert.pem()).unwrap();
+            fs::write(dir.join("key.pem"), generated.signing_key.serialize_pem()).unwrap();
+
+            TestCert { dir, cert: generated.cert.der().clone() }
+        }
+
+        fn cert_path(&self) -> PathBuf {
+            self.dir.join("cert.pem")
+        }
+
+        fn key_path(&self) -> PathBuf {
+            self.dir.join("key.pem")
+        }
+
+        fn paths(&self) -> (PathBuf, PathBuf) {
+            (self.cert_path(), self.key_path())
+        }
+
+        // A server config using this as the default certificate
+        fn config(&self) -> Config {
+            Config {
+                tls_cert: Some(self.cert_path()),
+                tls_key: Some(self.key_path()),
+                ..Config::default()
+            }
+        }
+    }
+
+    impl Drop for TestCert {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+
+    fn load(config: Config) -> io::Result<Arc<ServerConfig>> {
+        server_config(&config).map(Option::unwrap)
+    }
+
+    // Connect to `name`, trusting `trusted`
+    fn connect(address: SocketAddr, trusted: &[&TestCert], name: &str) -> StreamOwned<ClientConnection, TcpStream> {
+        let mut roots = RootCertStore::empty();
+
+        for cert in trusted {
+            roots.add(cert.cert.clone()).unwrap();
+        }
+
+        let mut config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        let name = ServerName::try_from(name.to_string()).unwrap();
+        let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+
+        StreamOwned::new(connection, TcpStream::connect(address).unwrap())
+    }
+
+
+    #[test]
+    fn loads_pem_certificate_and_key() {
+        let cert = TestCert::new("load", &["localhost"]);
+        let config = load(cert.config()).unwrap();
+
+        assert_eq!(config.alpn_protocols, vec![b"http/1.1".to_vec()]);
+        assert!(server_config(&Config::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_missing_and_mismatched_files() {
+        let cert = TestCert::new("mismatch", &["localhost"]);
+        let other = TestCert::new("mismatch-other", &["localhost"]);
+
+        let missing = load(Config { tls_cert: Some(cert.dir.join("nope.pem")), ..cert.config() });
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        // A key file holds no certificates
+        let swapped = load(Config { tls_cert: Some(cert.key_path()), ..cert.config() });
+        assert_eq!(swapped.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mismatched = load(Config { tls_key: Some(other.key_path()), ..cert.config() });
+        assert_eq!(mismatched.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let hosts = HashMap::from([("example.com".to_string(), (cert.cert_path(), other.key_path()))]);
+        let mismatched_host = load(Config { tls_hosts: hosts, ..Config::default() });
+        assert_eq!(mismatched_host.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn serves_requests_over_tls() {
+        let cert = TestCert::new("serve", &["localhost"]);
+        let address = start_server(cert.config());
+        let mut stream = connect(address, &[&cert], "localhost");
+
+        // Two requests on the same connection, the second pipelined right behind the first
+        stream.write_all(b"GET /first HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let (head, body) = read_response(&mut stream);
+
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert_eq!(body, "/first");
+
+        stream.write_all(b"GET /second HTTP/1.1\r\nHost: localhost\r\n\r\nGET /third HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut stream).1, "/second");
+        assert_eq!(read_response(&mut stream).1, "/third");
+
+        assert_eq!(stream.conn.alpn_protocol(), Some(&b"http/1.1"[..]));
+    }
+
+    #[test]
+    fn drops_plain_http_connections() {
+        let cert = TestCert::new("plain", &["localhost"]);
+        let address = start_server(cert.config());
+        let mut stream = TcpStream::connect(address).unwrap();
+
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+        // Whatever comes back (an alert, at most) isn't an HTTP response
+        let mut response = vec![];
+        let _ = stream.read_to_end(&mut response);
+        assert!(!response.starts_with(b"HTTP/"));
+    }
+
+    #[test]
+    fn matches_exact_and_wildcard_names() {
+        let exact = TestCert::new("match-exact", &["example.com"]);
+        let wildcard = TestCert::new("match-wildcard", &["*.example.com"]);
+        let provider = rustls::crypto::ring::default_provider();
+
+        let exact_key = load_key(&exact.cert_path(), &exact.key_path(), &provider).unwrap();
+        let wildcard_key = load_key(&wildcard.cert_path(), &wildcard.key_path(), &provider).unwrap();
+
+        let resolver = CertResolver {
+            hosts: HashMap::from([
+                ("example.com".to_string(), exact_key.clone()),
+                ("*.example.com".to_string(), wildcard_key.clone()),
+                ("api.example.com".to_string(), exact_key.clone())
+            ]),
+            default: None
+        };
+
+        assert!(Arc::ptr_eq(resolver.find("example.com").unwrap(), &exact_key));
+        assert!(Arc::ptr_eq(resolver.find("WWW.Example.com.").unwrap(), &wildcard_key));
+        assert!(Arc::ptr_eq(resolver.find("api.example.com").unwrap(), &exact_key));
+        assert!(resolver.find("a.b.example.com").is_none());
+        assert!(resolver.find("example.org").is_none());
+    }
+
+    #[test]
+    fn picks_certificate_by_server_name() {
+        let default = TestCert::new("sni-default", &["localhost"]);
+        let exact = TestCert::new("sni-exact", &["example.com"]);
+        let wildcard = TestCert::new("sni-wildcard", &["*.example.com"]);
+
+        let address = start_server(Config {
+            tls_hosts: HashMap::from([
+                ("example.com".to_string(), exact.paths()),
+                ("*.Example.com".to_string(), wildcard.paths())
+            ]),
+            ..default.config()
+        });
+
+        let trusted = [&default, &exact, &wildcard];
+
+        for (name, expected) in [("example.com", &exact), ("www.example.com", &wildcard), ("localhost", &default)] {
+            let mut stream = connect(address, &trusted, name);
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+            read_response(&mut stream);
+
+            assert_eq!(stream.conn.peer_certificates().unwrap()[0], expected.cert, "{}", name);
+        }
+    }
+
+    #[test]
+    fn reloads_changed_certificates_for_new_connections() {
+        let cert = TestCert::new("reload", &["localhost"]);
+        let renewed = TestCert::new("reload-renewed", &["localhost"]);
+
+        let address = start_server(Config {
+            tls_reload_interval: Duration::from_millis(50),
+            ..cert.config()
+        });
+
+        let mut old = connect(address, &[&cert], "localhost");
+        old.write_all(b"GET /old HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut old).1, "/old");
+
+        fs::copy(renewed.cert_path(), cert.cert_path()).unwrap();
+        fs::copy(renewed.key_path(), cert.key_path()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        let mut new = connect(address, &[&renewed], "localhost");
+        new.write_all(b"GET /new HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut new).1, "/new");
+        assert_eq!(new.conn.peer_certificates().unwrap()[0], renewed.cert);
+
+        // Connections from before the reload carry on as they were
+        old.write_all(b"GET /still-old HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut old).1, "/still-old");
+
+        // A broken certificate is ignored, the last good one stays in use
+        fs::write(cert.cert_path(), "not a certificate").unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        let mut after = connect(address, &[&renewed], "localhost");
+        after.write_all(b"GET /after HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut after).1, "/after");
+    }
+}
@@ -0,0 +1,749 @@
+// WebSocket (RFC 6455) connections, upgraded from regular HTTP requests
+
+use std::{collections::HashSet, net::SocketAddr, sync::Arc};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use sha1::{Digest, Sha1};
+
+use crate::{config::Config, deflate::{self, Deflate}, logging::Logger, request::{Method, Request}, response::{Builder, Response, Status}};
+
+
+// Appended to the client's key to build the Sec-WebSocket-Accept header
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Close codes
+#[allow(dead_code)]
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_NO_STATUS: u16 = 1005;
+pub const CLOSE_ABNORMAL: u16 = 1006;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+// Frame opcodes
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+// Set on the first frame of compressed messages
+const RSV1: u8 = 0x40;
+
+
+// Called for every event on a WebSocket connection
+pub type Handler = dyn Fn(&mut WebSocket, Event, Arc<Config>, Logger) + Send + Sync;
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>)
+}
+
+
+#[derive(Debug)]
+pub enum Event {
+    Open(Request), // The handshake request, sent before any messages
+    Message(Message),
+    Close(u16, String) // Close code & reason, sent once the connection is done for
+}
+
+
+// Topic changes & publishes requested by a handler, carried out by the server once it returns
+#[derive(Debug)]
+pub enum Action {
+    Subscribe(String),
+    Unsubscribe(String),
+    Publish(String, Message)
+}
+
+
+// `true` if the request is asking to be upgraded to a WebSocket connection
+pub fn is_upgrade(request: &Request) -> bool {
+    request.headers.has_token("Upgrade", "websocket")
+}
+
+
+// Build the response accepting a WebSocket handshake (along with the compression state, if
+// permessage-deflate was agreed on), or `None` if the handshake is invalid
+pub fn handshake(request: &Request, config: &Config) -> Option<(Response, Option<Deflate>)> {
+    let key = request.headers.get("Sec-WebSocket-Key")?.trim_ascii();
+
+    let valid = request.method == Method::Get
+        && request.version > 0
+        && request.headers.has_token("Connection", "upgrade")
+        && request.headers.get("Sec-WebSocket-Version").is_some_and(|v| v.trim_ascii() == b"13")
+        && BASE64.decode(key).is_ok_and(|key| key.len() == 16);
+
+    if !valid {
+        return None;
+    }
+
+    let mut response = Builder::with_status(Status::SwitchingProtocols)
+        .add_header("Upgrade", "websocket")
+        .add_header("Connection", "Upgrade")
+        .add_header("Sec-WebSocket-Accept", accept_key(key));
+
+    // Negotiating sets up the compression state, which isn't worth it if it'd be thrown away
+    let negotiated = match config.websocket_deflate {
+        true => deflate::negotiate(&extensions(request), config.deflate_no_context_takeover),
+        false => None
+    };
+
+    let deflate = match negotiated {
+        Some((deflate, extension)) => {
+            response = response.add_header("Sec-WebSocket-Extensions", extension);
+            Some(deflate)
+        },
+        None => None
+    };
+
+    Some((response.build(), deflate))
+}
+
+
+// Every extension the client offered, from all of its Sec-WebSocket-Extensions headers
+fn extensions(request: &Request) -> String {
+    request.headers.get_all("Sec-WebSocket-Extensions")
+        .filter_map(|value| std::str::from_utf8(value).ok())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+
+// The Sec-WebSocket-Accept value for a client's Sec-WebSocket-Key
+fn accept_key(key: &[u8]) -> String {
+    let mut sha1 = Sha1::new();
+
+    sha1.update(key);
+    sha1.update(ACCEPT_GUID.as_bytes());
+    BASE64.encode(sha1.finalize())
+}
+
+
+// A single frame received from the client, already unmasked
+#[derive(Debug)]
+struct Frame {
+    fin: bool,
+    compressed: bool, // RSV1, only allowed once permessage-deflate has been agreed on
+    opcode: u8,
+    payload: Vec<u8>
+}
+
+// Parse a frame from the front of `buffer`, returning it with its length in bytes. Errors hold
+// the close code & reason to fail the connection with.
+fn parse_frame(buffer: &[u8], max_size: usize, compression: bool) -> Result<Option<(Frame, usize)>, (u16, &'static str)> {
+    let [first, second, ..] = *buffer else { return Ok(None) };
+
+    let fin = first & 0x80 != 0;
+    let compressed = first & RSV1 != 0;
+    let opcode = first & 0x0F;
+
+    if first & 0x30 != 0 || (compressed && !compression) {
+        return Err((CLOSE_PROTOCOL_ERROR, "Reserved bits set"));
+    }
+
+    if second & 0x80 == 0 {
+        return Err((CLOSE_PROTOCOL_ERROR, "Client frames must be masked"));
+    }
+
+    let (len, mut pos) = match second & 0x7F {
+        126 => match buffer.get(2..4) {
+            Some(len) => (u16::from_be_bytes([len[0], len[1]]) as u64, 4),
+            None => return Ok(None)
+        },
+        127 => match buffer.get(2..10) {
+            Some(len) => (u64::from_be_bytes(len.try_into().unwrap()), 10),
+            None => return Ok(None)
+        },
+        len => (len as u64, 2)
+    };
+
+    // Control frames can't be fragmented or carry more than 125 bytes
+    if opcode & 0x08 != 0 && (!fin || len > 125) {
+        return Err((CLOSE_PROTOCOL_ERROR, "Invalid control frame"));
+    }
+
+    if len > max_size as u64 {
+        return Err((CLOSE_TOO_BIG, "Message too big"));
+    }
+
+    let len = len as usize;
+
+    let Some(mask) = buffer.get(pos..pos + 4) else { return Ok(None) };
+    let mask = [mask[0], mask[1], mask[2], mask[3]];
+    pos += 4;
+
+    let Some(payload) = buffer.get(pos..pos + len) else { return Ok(None) };
+
+    let payload = payload.iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+
+    Ok(Some((Frame { fin, compressed, opcode, payload }, pos + len)))
+}
+
+
+// Encode a single, unfragmented & unmasked frame (as sent by servers). `opcode` may have RSV1 set.
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        },
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    frame.extend_from_slice(payload);
+    frame
+}
+
+
+// `true` for close codes a peer is allowed to send
+fn valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+}
+
+
+// A WebSocket connection, owned by the Client it was upgraded from
+#[derive(Debug)]
+pub struct WebSocket {
+    id: usize,
+    peer: SocketAddr,
+    max_message_size: usize,
+    deflate: Option<Deflate>,
+    fragments: Option<(u8, bool, Vec<u8>)>, // Opcode, compression & data of a message that's still being received
+    output: Vec<u8>, // Encoded frames waiting to be written
+    topics: HashSet<String>, // Topics this connection is subscribed to
+    actions: Vec<Action>,
+    close_sent: bool,
+    closed: bool // The close handshake is done (or failed), the connection should be dropped
+}
+
+impl WebSocket {
+    pub fn new(id: usize, peer: SocketAddr, max_message_size: usize, deflate: Option<Deflate>) -> Self {
+        WebSocket {
+            id,
+            peer,
+            max_message_size,
+            deflate,
+            fragments: None,
+            output: vec![],
+            topics: HashSet::new(),
+            actions: vec![],
+            close_sent: false,
+            closed: false
+        }
+    }
+
+    // Key of the connection within the server
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn send(&mut self, message: Message) {
+        if self.close_sent {
+            return;
+        }
+
+        let (opcode, data) = match &message {
+            Message::Text(text) => (OP_TEXT, text.as_bytes()),
+            Message::Binary(data) => (OP_BINARY, data.as_slice())
+        };
+
+        let frame = match &mut self.deflate {
+            Some(deflate) => match deflate.compress(data) {
+                Ok(compressed) => encode_frame(opcode | RSV1, &compressed),
+                Err(_) => {
+                    self.close(CLOSE_INTERNAL_ERROR, "Compression failed");
+                    self.closed = true;
+                    return;
+                }
+            },
+            None => encode_frame(opcode, data)
+        };
+
+        self.output.extend(frame);
+    }
+
+    // Receive every message published to `topic` from now on
+    pub fn subscribe(&mut self, topic: &str) {
+        if self.topics.insert(topic.to_string()) {
+            self.actions.push(Action::Subscribe(topic.to_string()));
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn unsubscribe(&mut self, topic: &str) {
+        if self.topics.remove(topic) {
+            self.actions.push(Action::Unsubscribe(topic.to_string()));
+        }
+    }
+
+    pub fn topics(&self) -> impl Iterator<Item = &str> {
+        self.topics.iter().map(String::as_str)
+    }
+
+    // Send `message` to every connection subscribed to `topic` (this one included, if it's
+    // subscribed) once the handler returns
+    pub fn publish(&mut self, topic: &str, message: Message) {
+        self.actions.push(Action::Publish(topic.to_string(), message));
+    }
+
+    // Take the actions requested since the last call, oldest first
+    pub fn take_actions(&mut self) -> Vec<Action> {
+        std::mem::take(&mut self.actions)
+    }
+
+    // Start the close handshake, the connection is dropped once the client answers
+    pub fn close(&mut self, code: u16, reason: &str) {
+        if self.close_sent {
+            return;
+        }
+
+        // The whole close payload has to fit in a control frame
+        let mut end = reason.len().min(123);
+
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(&reason.as_bytes()[0..end]);
+
+        self.output.extend(encode_frame(OP_CLOSE, &payload));
+        self.close_sent = true;
+    }
+
+    // `true` once the connection should be dropped (after writing any remaining output)
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    // Take the frames waiting to be written
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    // Parse frames from the front of `buffer` until a complete message or close arrives. Pings
+    // are answered along the way. Returns `None` if more data is needed.
+    pub fn next_event(&mut self, buffer: &mut Vec<u8>) -> Option<Event> {
+        while !self.closed {
+            // Control frames can always be received, no matter how much of a message is buffered
+            let buffered = self.fragments.as_ref().map_or(0, |(_, _, data)| data.len());
+            let limit = self.max_message_size.saturating_sub(buffered).max(125);
+
+            let frame = match parse_frame(buffer, limit, self.deflate.is_some()) {
+                Ok(Some((frame, len))) => {
+                    buffer.drain(0..len);
+                    frame
+                },
+                Ok(None) => return None,
+                Err((code, reason)) => return Some(self.fail(code, reason))
+            };
+
+            // Only the first frame of a data message says whether it's compressed
+            if frame.compressed && !matches!(frame.opcode, OP_TEXT | OP_BINARY) {
+                return Some(self.fail(CLOSE_PROTOCOL_ERROR, "Reserved bits set"));
+            }
+
+            match frame.opcode {
+                OP_PING => {
+                    if !self.close_sent {
+                        self.output.extend(encode_frame(OP_PONG, &frame.payload));
+                    }
+                },
+
+                OP_PONG => (),
+
+                OP_CLOSE => return Some(self.receive_close(&frame.payload)),
+
+                OP_TEXT | OP_BINARY if self.fragments.is_none() && !frame.fin => {
+                    self.fragments = Some((frame.opcode, frame.compressed, frame.payload));
+                },
+
+                OP_TEXT | OP_BINARY if self.fragments.is_none() => {
+                    if let Some(event) = self.message(frame.opcode, frame.compressed, frame.payload) {
+                        return Some(event);
+                    }
+                },
+
+                OP_CONTINUATION if self.fragments.is_some() => {
+                    let (_, _, data) = self.fragments.as_mut().unwrap();
+
+                    if data.len() + frame.payload.len() > self.max_message_size {
+                        return Some(self.fail(CLOSE_TOO_BIG, "Message too big"));
+                    }
+
+                    data.extend(frame.payload);
+
+                    if frame.fin {
+                        let (opcode, compressed, data) = self.fragments.take().unwrap();
+
+                        if let Some(event) = self.message(opcode, compressed, data) {
+                            return Some(event);
+                        }
+                    }
+                },
+
+                _ => return Some(self.fail(CLOSE_PROTOCOL_ERROR, "Unexpected opcode"))
+            }
+        }
+
+        None
+    }
+
+    // Turn a complete message into an Event. Messages arriving after we've sent a close frame are
+    // dropped (`None`), parsing carries on since the client's close frame may be right behind them.
+    fn message(&mut self, opcode: u8, compressed: bool, data: Vec<u8>) -> Option<Event> {
+        if self.close_sent {
+            return None;
+        }
+
+        let data = match (compressed, &mut self.deflate) {
+            (true, Some(deflate)) => match deflate.decompress(&data, self.max_message_size) {
+                Ok(data) => data,
+                Err((code, reason)) => return Some(self.fail(code, reason))
+            },
+            _ => data
+        };
+
+        match opcode {
+            OP_TEXT => match String::from_utf8(data) {
+                Ok(text) => Some(Event::Message(Message::Text(text))),
+                Err(_) => Some(self.fail(CLOSE_INVALID_DATA, "Invalid UTF-8 in text message"))
+            },
+            _ => Some(Event::Message(Message::Binary(data)))
+        }
+    }
+
+    // Handle the client's close frame, echoing it back if we haven't sent our own
+    fn receive_close(&mut self, payload: &[u8]) -> Event {
+        let (code, reason) = match payload {
+            [] => (CLOSE_NO_STATUS, String::new()),
+            [_] => return self.fail(CLOSE_PROTOCOL_ERROR, "Invalid close payload"),
+
+            [high, low, reason @ ..] => {
+                let code = u16::from_be_bytes([*high, *low]);
+
+                if !valid_close_code(code) {
+                    return self.fail(CLOSE_PROTOCOL_ERROR, "Invalid close code");
+                }
+
+                match std::str::from_utf8(reason) {
+                    Ok(reason) => (code, reason.to_string()),
+                    Err(_) => return self.fail(CLOSE_INVALID_DATA, "Invalid UTF-8 in close reason")
+                }
+            }
+        };
+
+        if !self.close_sent {
+            match code {
+                CLOSE_NO_STATUS => self.output.extend(encode_frame(OP_CLOSE, &[])),
+                _ => self.close(code, "")
+            }
+        }
+
+        self.close_sent = true;
+        self.closed = true;
+        Event::Close(code, reason)
+    }
+
+    // Fail the connection, sending a close frame if possible
+    fn fail(&mut self, code: u16, reason: &str) -> Event {
+        self.close(code, reason);
+        self.closed = true;
+        Event::Close(code, reason.to_string())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::{
+        accept_key,
+        handshake,
+        parse_frame,
+        Event,
+        Message,
+        WebSocket,
+        CLOSE_GOING_AWAY,
+        CLOSE_INVALID_DATA,
+        CLOSE_NORMAL,
+        CLOSE_PROTOCOL_ERROR,
+        CLOSE_TOO_BIG,
+        OP_BINARY,
+        OP_CLOSE,
+        OP_CONTINUATION,
+        OP_PING,
+        OP_PONG,
+        OP_TEXT,
+        RSV1
+    };
+    use crate::{config::Config, request::{Head, Headers, Method, Request}, response::Response};
+
+
+    // Encode a frame the way a client would, masked. `first` is the first byte, FIN & RSV bits
+    // included.
+    fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![first];
+
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn websocket() -> WebSocket {
+        WebSocket::new(1, SocketAddr::from((Ipv4Addr::LOCALHOST, 1234)), 1024, None)
+    }
+
+
+    #[test]
+    fn finishes_the_close_handshake_behind_a_dropped_message() {
+        let mut socket = websocket();
+        socket.close(CLOSE_NORMAL, "bye");
+        socket.take_output();
+
+        // A message the client sent before seeing our close, arriving along with its close reply
+        let mut buffer = client_frame(0x80 | OP_TEXT, b"too late");
+        buffer.extend(client_frame(0x80 | OP_CLOSE, &CLOSE_NORMAL.to_be_bytes()));
+
+        assert!(matches!(socket.next_event(&mut buffer), Some(Event::Close(CLOSE_NORMAL, _))));
+        assert!(buffer.is_empty());
+        assert!(socket.is_closed());
+
+        // Our close frame already went out, it isn't sent twice
+        assert!(socket.take_output().is_empty());
+        assert!(socket.next_event(&mut buffer).is_none());
+    }
+
+    #[test]
+    fn builds_the_accept_key() {
+        // The example from RFC 6455, section 1.3
+        assert_eq!(accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn parses_masked_frames() {
+        let mut buffer = client_frame(0x80 | OP_BINARY, &[1, 2, 3]);
+        buffer.extend_from_slice(&[0x81]);
+
+        let (frame, len) = parse_frame(&buffer, 1024, false).unwrap().unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, OP_BINARY);
+        assert_eq!(frame.payload, [1, 2, 3]);
+        assert_eq!(len, buffer.len() - 1);
+
+        // Extended lengths, and frames that haven't fully arrived yet
+        let long = client_frame(0x80 | OP_TEXT, &[b'a'; 300]);
+        assert_eq!(parse_frame(&long, 1024, false).unwrap().unwrap().0.payload.len(), 300);
+
+        for end in [0, 1, 3, 8, long.len() - 1] {
+            assert!(parse_frame(&long[..end], 1024, false).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_frames() {
+        let error = |buffer: &[u8], compression| parse_frame(buffer, 1024, compression).unwrap_err();
+
+        // Unmasked
+        assert_eq!(error(&[0x81, 0x01, b'a'], false).0, CLOSE_PROTOCOL_ERROR);
+
+        // Reserved bits, RSV1 only being allowed with compression
+        assert_eq!(error(&client_frame(0x80 | 0x20 | OP_TEXT, b"a"), true).0, CLOSE_PROTOCOL_ERROR);
+        assert_eq!(error(&client_frame(0x80 | 0x10 | OP_TEXT, b"a"), true).0, CLOSE_PROTOCOL_ERROR);
+        assert_eq!(error(&client_frame(0x80 | RSV1 | OP_TEXT, b"a"), false).0, CLOSE_PROTOCOL_ERROR);
+        assert!(parse_frame(&client_frame(0x80 | RSV1 | OP_TEXT, b"a"), 1024, true).is_ok());
+
+        // Control frames over 125 bytes or fragmented
+        assert_eq!(error(&client_frame(0x80 | OP_PING, &[0; 126]), false).0, CLOSE_PROTOCOL_ERROR);
+        assert_eq!(error(&client_frame(OP_PING, b"a"), false).0, CLOSE_PROTOCOL_ERROR);
+
+        // Too big, found out from the length before the payload arrives
+        let big = client_frame(0x80 | OP_BINARY, &[0; 2000]);
+        assert_eq!(error(&big[..20], false).0, CLOSE_TOO_BIG);
+    }
+
+    #[test]
+    fn reassembles_fragmented_messages() {
+        let mut socket = websocket();
+
+        // Control frames can come in between the fragments
+        let mut buffer = client_frame(OP_TEXT, b"Hel");
+        buffer.extend(client_frame(0x80 | OP_PING, b"?"));
+        buffer.extend(client_frame(OP_CONTINUATION, b"lo, "));
+        buffer.extend(client_frame(0x80 | OP_PONG, b""));
+        buffer.extend(client_frame(0x80 | OP_CONTINUATION, b"world"));
+        buffer.extend(client_frame(0x80 | OP_BINARY, &[1]));
+
+        match socket.next_event(&mut buffer) {
+            Some(Event::Message(message)) => assert_eq!(message, Message::Text(String::from("Hello, world"))),
+            event => panic!("Unexpected event: {:?}", event)
+        }
+
+        assert_eq!(socket.take_output(), [0x80 | OP_PONG, 1, b'?']);
+
+        match socket.next_event(&mut buffer) {
+            Some(Event::Message(message)) => assert_eq!(message, Message::Binary(vec![1])),
+            event => panic!("Unexpected event: {:?}", event)
+        }
+
+        assert!(buffer.is_empty());
+        assert!(!socket.is_closed());
+    }
+
+    #[test]
+    fn rejects_broken_fragmentation() {
+        // A continuation with nothing to continue
+        let mut socket = websocket();
+        let mut buffer = client_frame(0x80 | OP_CONTINUATION, b"a");
+        assert!(matches!(socket.next_event(&mut buffer), Some(Event::Close(CLOSE_PROTOCOL_ERROR, _))));
+
+        // A new message before the last one is done
+        let mut socket = websocket();
+        let mut buffer = client_frame(OP_TEXT, b"a");
+        buffer.extend(client_frame(0x80 | OP_TEXT, b"b"));
+        assert!(matches!(socket.next_event(&mut buffer), Some(Event::Close(CLOSE_PROTOCOL_ERROR, _))));
+
+        // Fragments that add up to more than the limit
+        let mut socket = websocket();
+        let mut buffer = client_frame(OP_BINARY, &[0; 1000]);
+        buffer.extend(client_frame(0x80 | OP_CONTINUATION, &[0; 100]));
+        assert!(matches!(socket.next_event(&mut buffer), Some(Event::Close(CLOSE_TOO_BIG, _))));
+        assert!(socket.is_closed());
+    }
+
+    #[test]
+    fn answers_pings_with_pongs() {
+        let mut socket = websocket();
+        let mut buffer = client_frame(0x80 | OP_PING, b"are you there");
+
+        // Nothing for the handler, the pong is sent right away
+        assert!(socket.next_event(&mut buffer).is_none());
+        assert!(buffer.is_empty());
+
+        let mut expected = vec![0x80 | OP_PONG, 13];
+        expected.extend_from_slice(b"are you there");
+        assert_eq!(socket.take_output(), expected);
+    }
+
+    #[test]
+    fn echoes_the_clients_close() {
+        let mut socket = websocket();
+        let mut payload = CLOSE_GOING_AWAY.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"leaving");
+
+        let mut buffer = client_frame(0x80 | OP_CLOSE, &payload);
+
+        match socket.next_event(&mut buffer) {
+            Some(Event::Close(code, reason)) => assert_eq!((code, reason.as_str()), (CLOSE_GOING_AWAY, "leaving")),
+            event => panic!("Unexpected event: {:?}", event)
+        }
+
+        // The code is echoed back, and nothing else is sent after it
+        let mut expected = vec![0x80 | OP_CLOSE, 2];
+        expected.extend_from_slice(&CLOSE_GOING_AWAY.to_be_bytes());
+        assert_eq!(socket.take_output(), expected);
+
+        socket.send(Message::Text(String::from("hello?")));
+        assert!(socket.take_output().is_empty());
+        assert!(socket.is_closed());
+    }
+
+    #[test]
+    fn waits_for_the_client_to_answer_our_close() {
+        let mut socket = websocket();
+        socket.close(CLOSE_NORMAL, "done");
+
+        let mut expected = vec![0x80 | OP_CLOSE, 6];
+        expected.extend_from_slice(&CLOSE_NORMAL.to_be_bytes());
+        expected.extend_from_slice(b"done");
+        assert_eq!(socket.take_output(), expected);
+
+        // Still open until the client answers, pings aren't answered anymore
+        let mut buffer = client_frame(0x80 | OP_PING, b"");
+        assert!(socket.next_event(&mut buffer).is_none());
+        assert!(!socket.is_closed());
+
+        let mut buffer = client_frame(0x80 | OP_CLOSE, &CLOSE_NORMAL.to_be_bytes());
+        assert!(matches!(socket.next_event(&mut buffer), Some(Event::Close(CLOSE_NORMAL, _))));
+        assert!(socket.is_closed());
+        assert!(socket.take_output().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_close_frames() {
+        // Half a code, 1005 (which is only for reporting a missing code) & a reason that isn't UTF-8
+        for (payload, expected) in [(&[0x03][..], CLOSE_PROTOCOL_ERROR), (&[0x03, 0xED], CLOSE_PROTOCOL_ERROR), (&[0x03, 0xE8, 0xFF], CLOSE_INVALID_DATA)] {
+            let mut socket = websocket();
+            let mut buffer = client_frame(0x80 | OP_CLOSE, payload);
+
+            match socket.next_event(&mut buffer) {
+                Some(Event::Close(code, _)) => assert_eq!(code, expected, "{:?}", payload),
+                event => panic!("Unexpected event: {:?}", event)
+            }
+
+            assert!(socket.is_closed());
+        }
+    }
+
+    #[test]
+    fn only_negotiates_compression_when_enabled() {
+        let mut headers = Headers::default();
+
+        for (name, value) in [
+            ("Upgrade", "websocket"),
+            ("Connection", "Upgrade"),
+            ("Sec-WebSocket-Version", "13"),
+            ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+            ("Sec-WebSocket-Extensions", "permessage-deflate; client_max_window_bits")
+        ] {
+            headers.push(name.to_string(), value.as_bytes().to_vec());
+        }
+
+        let head = Head { method: Method::Get, target: String::from("/"), version: 1, headers };
+        let request = Request::new(head, vec![], SocketAddr::from((Ipv4Addr::LOCALHOST, 1234)), 1);
+
+        let head = |response: Response| String::from_utf8(response.encode(1, true).next().unwrap().unwrap()).unwrap();
+
+        let (response, deflate) = handshake(&request, &Config::default()).unwrap();
+        assert!(deflate.is_some());
+        assert!(head(response).contains("\r\nSec-WebSocket-Extensions: permessage-deflate\r\n"));
+
+        let (response, deflate) = handshake(&request, &Config { websocket_deflate: false, ..Config::default() }).unwrap();
+        assert!(deflate.is_none());
+        assert!(!head(response).contains("Sec-WebSocket-Extensions"));
+    }
+}